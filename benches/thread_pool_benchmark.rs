@@ -0,0 +1,37 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use crossbeam::sync::WaitGroup;
+use kvs::thread_pool::{QueueThreadPool, StealingThreadPool, ThreadPool};
+
+const JOBS: usize = 10_000;
+
+// run many tiny jobs and wait until all of them are done
+fn run_jobs<P: ThreadPool>(pool: &P) {
+    let wg = WaitGroup::new();
+    for i in 0..JOBS {
+        let wg = wg.clone();
+        pool.execute(move || {
+            criterion::black_box(i * i);
+            drop(wg);
+        })
+        .unwrap();
+    }
+    wg.wait();
+}
+
+pub fn thread_pool_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("thread_pool_bench");
+    for threads in &[2, 4, 8] {
+        group.bench_with_input(BenchmarkId::new("queue", threads), threads, |b, &n| {
+            let pool = QueueThreadPool::new(n).unwrap();
+            b.iter(|| run_jobs(&pool));
+        });
+        group.bench_with_input(BenchmarkId::new("stealing", threads), threads, |b, &n| {
+            let pool = StealingThreadPool::new(n).unwrap();
+            b.iter(|| run_jobs(&pool));
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, thread_pool_bench);
+criterion_main!(benches);
@@ -0,0 +1,185 @@
+use crate::error::{Error, ErrorKind, Result};
+use pbkdf2::pbkdf2_hmac;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const ITERATIONS: u32 = 100_000;
+const SCHEME: &str = "pbkdf2-sha256";
+// checked for unknown names, so they take as long as a wrong password
+const DUMMY_HASH: &str = "pbkdf2-sha256$100000$00000000000000000000000000000000$\
+    0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Users allowed to talk to server, loaded from a json file:
+/// ```json
+/// [
+///     {
+///         "name": "alice",
+///         "password": "pbkdf2-sha256$100000$<salt>$<hash>",
+///         "rules": [{ "prefix": "app:", "access": "read-write" }]
+///     }
+/// ]
+/// ```
+#[derive(Debug, Clone)]
+pub struct Users {
+    users: Vec<User>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct User {
+    pub name: String,
+    /// hashed by `hash_password`
+    pub password: String,
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+}
+
+/// Grants access to keys starting with `prefix`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Rule {
+    pub prefix: String,
+    pub access: Access,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Access {
+    ReadOnly,
+    ReadWrite,
+}
+
+impl Users {
+    pub fn new(users: Vec<User>) -> Self {
+        Users { users }
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let users: Vec<User> = serde_json::from_str(&fs::read_to_string(path)?)?;
+        for user in &users {
+            parse_hash(&user.password)?;
+        }
+        Ok(Users::new(users))
+    }
+
+    /// find user with matching password.
+    /// A password is hashed for unknown names too, so timing does not tell which names exist.
+    pub fn authenticate(&self, name: &str, password: &str) -> Result<&User> {
+        let user = self.users.iter().find(|user| user.name == name);
+        let hashed = user.map_or(DUMMY_HASH, |user| user.password.as_str());
+        match (verify_password(password, hashed), user) {
+            (true, Some(user)) => Ok(user),
+            _ => Err(Error::from(ErrorKind::Unauthenticated(
+                "invalid user name or password".to_string(),
+            ))),
+        }
+    }
+}
+
+impl User {
+    /// whether user may read, or also write when `write` is set, the given key.
+    /// The rule with the longest matching prefix wins.
+    pub fn allows(&self, key: &str, write: bool) -> bool {
+        let rule = self
+            .rules
+            .iter()
+            .filter(|rule| key.starts_with(&rule.prefix))
+            .max_by_key(|rule| rule.prefix.len());
+
+        match rule {
+            Some(rule) => !write || rule.access == Access::ReadWrite,
+            None => false,
+        }
+    }
+}
+
+/// Hash a password with a random salt, to be stored in users file
+pub fn hash_password(password: &str) -> String {
+    let mut salt = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let hash = derive(password, &salt, ITERATIONS);
+    format!(
+        "{}${}${}${}",
+        SCHEME,
+        ITERATIONS,
+        to_hex(&salt),
+        to_hex(&hash)
+    )
+}
+
+/// Password read from `file`, or a line of stdin when it is `-`, otherwise from
+/// environment variable `var`. Passwords given as flags are visible to every local user.
+pub fn read_password(file: Option<&Path>, var: &str) -> Result<Option<String>> {
+    let password = match file {
+        Some(path) if path == Path::new("-") => {
+            let mut line = String::new();
+            io::stdin().read_line(&mut line)?;
+            line
+        }
+        Some(path) => fs::read_to_string(path)?,
+        None => match env::var(var) {
+            Ok(password) => password,
+            Err(_) => return Ok(None),
+        },
+    };
+    Ok(Some(
+        password.trim_end_matches(&['\n', '\r'][..]).to_owned(),
+    ))
+}
+
+pub fn verify_password(password: &str, hashed: &str) -> bool {
+    match parse_hash(hashed) {
+        Ok((iterations, salt, expected)) => {
+            let hash = derive(password, &salt, iterations);
+            // compare in constant time
+            hash.len() == expected.len()
+                && hash
+                    .iter()
+                    .zip(expected.iter())
+                    .fold(0, |acc, (a, b)| acc | (a ^ b))
+                    == 0
+        }
+        Err(_) => false,
+    }
+}
+
+fn derive(password: &str, salt: &[u8], iterations: u32) -> [u8; 32] {
+    let mut hash = [0u8; 32];
+    pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, iterations, &mut hash);
+    hash
+}
+
+// split a hash into iterations, salt and digest
+fn parse_hash(hashed: &str) -> Result<(u32, Vec<u8>, Vec<u8>)> {
+    let invalid = || Error::from(ErrorKind::InvalidFormat(format!("invalid hash {}", hashed)));
+
+    let parts: Vec<&str> = hashed.split('$').collect();
+    match parts[..] {
+        [SCHEME, iterations, salt, hash] => {
+            let iterations = iterations.parse().map_err(|_| invalid())?;
+            let salt = from_hex(salt).ok_or_else(invalid)?;
+            let hash = from_hex(hash).ok_or_else(invalid)?;
+            Ok((iterations, salt, hash))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    s.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [_, _] => std::str::from_utf8(pair)
+                .ok()
+                .and_then(|b| u8::from_str_radix(b, 16).ok()),
+            _ => None,
+        })
+        .collect()
+}
@@ -0,0 +1,133 @@
+//! Consistent copies of a `KvStore` directory, see `KvsEngine::backup`
+use crate::auth::to_hex;
+use crate::common::Command;
+use crate::error::{Error, ErrorKind, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+/// File describing a backup, next to the db files it holds
+pub const MANIFEST: &str = "backup.json";
+
+/// What a backup holds
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    /// sequence number of the last write included
+    pub seq: u64,
+    pub files: Vec<BackupFile>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BackupFile {
+    pub name: String,
+    pub len: u64,
+    /// hex encoded
+    pub sha256: String,
+}
+
+// copy db `files` of store at `src` into empty directory `dest`
+pub(crate) fn write(src: &Path, dest: &Path, seq: u64, files: &[u64]) -> Result<Manifest> {
+    require_empty(dest)?;
+    let files = files
+        .iter()
+        .map(|no| {
+            let name = format!("{}.db", no);
+            let (len, sha256) = copy_hashed(&src.join(&name), Some(&dest.join(&name)))?;
+            Ok(BackupFile { name, len, sha256 })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let manifest = Manifest { seq, files };
+
+    // manifest last, a backup without one is incomplete
+    let tmp = dest.join("backup.tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(&serde_json::to_vec(&manifest)?)?;
+    file.sync_all()?;
+    fs::rename(&tmp, dest.join(MANIFEST))?;
+    Ok(manifest)
+}
+
+/// check every file of backup at `backup` is whole and readable
+pub fn verify(backup: &Path) -> Result<Manifest> {
+    let manifest: Manifest = match fs::read(backup.join(MANIFEST)) {
+        Ok(buf) => serde_json::from_slice(&buf)?,
+        Err(e) => {
+            return Err(invalid(format!(
+                "{} is not a backup, {} can not be read: {}",
+                backup.display(),
+                MANIFEST,
+                e
+            )))
+        }
+    };
+    for file in &manifest.files {
+        let path = backup.join(&file.name);
+        let (len, sha256) = copy_hashed(&path, None)
+            .map_err(|e| invalid(format!("can not read {}: {}", path.display(), e)))?;
+        if len != file.len || sha256 != file.sha256 {
+            return Err(invalid(format!(
+                "{} changed since backup was made",
+                file.name
+            )));
+        }
+        let reader = BufReader::new(File::open(&path)?);
+        for command in serde_json::Deserializer::from_reader(reader).into_iter::<Command>() {
+            if let Err(e) = command {
+                return Err(invalid(format!("{} is corrupt: {}", file.name, e)));
+            }
+        }
+    }
+    Ok(manifest)
+}
+
+/// verify backup at `backup`, then copy it into empty directory `dest` to be opened as a store
+pub fn restore(backup: &Path, dest: &Path) -> Result<Manifest> {
+    let manifest = verify(backup)?;
+    require_empty(dest)?;
+    for file in &manifest.files {
+        copy_hashed(&backup.join(&file.name), Some(&dest.join(&file.name)))?;
+    }
+    Ok(manifest)
+}
+
+fn require_empty(dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    if fs::read_dir(dir)?.next().is_some() {
+        return Err(invalid(format!("{} is not empty", dir.display())));
+    }
+    Ok(())
+}
+
+// size and hash of file at `from`, copying it to `to` if given
+fn copy_hashed(from: &Path, to: Option<&Path>) -> Result<(u64, String)> {
+    let mut reader = BufReader::new(File::open(from)?);
+    let mut writer = match to {
+        Some(to) => Some(BufWriter::new(File::create(to)?)),
+        None => None,
+    };
+    let mut hasher = Sha256::new();
+    let mut buf = [0; 64 * 1024];
+    let mut len = 0;
+    loop {
+        let read = reader.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+        if let Some(writer) = &mut writer {
+            writer.write_all(&buf[..read])?;
+        }
+        len += read as u64;
+    }
+    if let Some(mut writer) = writer {
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+    }
+    Ok((len, to_hex(&hasher.finalize())))
+}
+
+fn invalid(message: String) -> Error {
+    Error::from(ErrorKind::InvalidFormat(message))
+}
@@ -0,0 +1,224 @@
+use clap::{crate_authors, crate_version, Clap};
+use kvs::{
+    backup,
+    error::{Error, ErrorKind, Result},
+    export::{self, Format},
+    inspect::{self, Repair},
+    kvs_store::KvStore,
+};
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+    process,
+};
+
+/// Maintain store directories of a stopped server
+#[derive(Clap)]
+#[clap(version =crate_version!() , author = crate_authors!())]
+struct Options {
+    #[clap(subcommand)]
+    subcmd: SubCommand,
+}
+#[derive(Clap)]
+enum SubCommand {
+    /// check a backup and copy it into an empty store directory
+    Restore(Restore),
+    /// write live pairs of a store to a file
+    Export(Transfer),
+    /// set pairs read from a file in a store, keeping pairs not in it
+    Import(Transfer),
+    /// list db files of a store with their records and live and stale bytes
+    Inspect(Store),
+    /// print records of a store with their offsets
+    Dump(Dump),
+    /// check every record of a store can be read, exiting with 2 if not
+    Verify(Store),
+    /// cut corrupt records off db files, keeping them in N.corrupt files
+    Repair(Store),
+}
+#[derive(Clap)]
+struct Restore {
+    /// directory made by a backup request
+    #[clap(parse(from_os_str))]
+    backup: PathBuf,
+    /// store directory to create, must be empty
+    #[clap(parse(from_os_str))]
+    dest: PathBuf,
+}
+
+#[derive(Clap)]
+struct Transfer {
+    /// store directory, created on import if missing
+    #[clap(parse(from_os_str))]
+    store: PathBuf,
+    #[clap(parse(from_os_str))]
+    file: PathBuf,
+    /// jsonl or csv
+    #[clap(long, default_value = "jsonl")]
+    format: Format,
+}
+
+#[derive(Clap)]
+struct Store {
+    /// store directory
+    #[clap(parse(from_os_str))]
+    store: PathBuf,
+}
+
+#[derive(Clap)]
+struct Dump {
+    /// store directory
+    #[clap(parse(from_os_str))]
+    store: PathBuf,
+    /// only records of N.db
+    #[clap(long)]
+    segment: Option<u64>,
+}
+
+fn main() {
+    let opts = Options::parse();
+    match opts.subcmd {
+        SubCommand::Restore(m) => match backup::restore(&m.backup, &m.dest) {
+            Ok(manifest) => println!(
+                "restored {} files, up to write {}",
+                manifest.files.len(),
+                manifest.seq
+            ),
+            Err(e) => fail(e),
+        },
+        SubCommand::Export(m) => match export_file(&m) {
+            Ok(count) => println!("exported {} pairs", count),
+            Err(e) => fail(e),
+        },
+        SubCommand::Import(m) => match import_file(&m) {
+            Ok(count) => println!("imported {} pairs", count),
+            Err(e) => fail(e),
+        },
+        SubCommand::Inspect(m) => {
+            if let Err(e) = print_segments(&m.store) {
+                fail(e);
+            }
+        }
+        SubCommand::Dump(m) => {
+            if let Err(e) = dump(&m) {
+                fail(e);
+            }
+        }
+        SubCommand::Verify(m) => {
+            match require_store(&m.store).and_then(|_| inspect::verify(&m.store)) {
+                Ok(corrupt) if corrupt.is_empty() => println!("ok"),
+                Ok(corrupt) => {
+                    for segment in corrupt {
+                        let corruption = segment.corrupt.unwrap();
+                        println!(
+                            "{}.db: corrupt at byte {} of {}: {}",
+                            segment.no, corruption.offset, segment.len, corruption.message
+                        );
+                    }
+                    process::exit(2);
+                }
+                Err(e) => fail(e),
+            }
+        }
+        SubCommand::Repair(m) => {
+            match require_store(&m.store).and_then(|_| inspect::repair(&m.store)) {
+                Ok(repairs) if repairs.is_empty() => println!("nothing to repair"),
+                Ok(repairs) => {
+                    for repair in repairs {
+                        match repair {
+                            Repair::Truncated { no, offset } => {
+                                println!("{}.db: cut off from byte {}", no, offset)
+                            }
+                            Repair::Dropped { no } => {
+                                println!("{}.db: set aside, nothing readable", no)
+                            }
+                        }
+                    }
+                }
+                Err(e) => fail(e),
+            }
+        }
+    }
+}
+
+fn print_segments(store: &Path) -> Result<()> {
+    require_store(store)?;
+    println!(
+        "{:<12} {:>12} {:>10} {:>12} {:>12}",
+        "segment", "bytes", "records", "live", "stale"
+    );
+    for segment in inspect::inspect(store)? {
+        println!(
+            "{:<12} {:>12} {:>10} {:>12} {:>12}",
+            format!("{}.db", segment.no),
+            segment.len,
+            segment.records,
+            segment.live_bytes,
+            segment.stale_bytes
+        );
+        if let Some(corruption) = segment.corrupt {
+            println!(
+                "  corrupt at byte {}: {}",
+                corruption.offset, corruption.message
+            );
+        }
+    }
+    Ok(())
+}
+
+fn dump(m: &Dump) -> Result<()> {
+    require_store(&m.store)?;
+    let segments = match m.segment {
+        Some(no) => vec![no],
+        None => inspect::segments(&m.store)?,
+    };
+    for no in segments {
+        let (records, corrupt) = inspect::read_segment(&m.store, no)?;
+        for record in records {
+            println!(
+                "{}.db {}..{} {}",
+                no,
+                record.start,
+                record.end,
+                serde_json::to_string(&record.command)?
+            );
+        }
+        if let Some(corruption) = corrupt {
+            println!(
+                "{}.db {}.. corrupt: {}",
+                no, corruption.offset, corruption.message
+            );
+        }
+    }
+    Ok(())
+}
+
+fn export_file(m: &Transfer) -> Result<usize> {
+    require_store(&m.store)?;
+    let store = KvStore::open(&m.store)?;
+    let file = BufWriter::new(File::create(&m.file)?);
+    export::export(&store, file, m.format)
+}
+
+fn import_file(m: &Transfer) -> Result<usize> {
+    let store = KvStore::open(&m.store)?;
+    let file = BufReader::new(File::open(&m.file)?);
+    export::import(&store, file, m.format)
+}
+
+// opening a missing store would create it
+fn require_store(store: &Path) -> Result<()> {
+    if !store.is_dir() {
+        return Err(Error::from(ErrorKind::Storage(format!(
+            "{} is not a store directory",
+            store.display()
+        ))));
+    }
+    Ok(())
+}
+
+fn fail(e: Error) -> ! {
+    eprintln!("{}", e);
+    process::exit(1);
+}
@@ -1,6 +1,11 @@
 use clap::{crate_authors, crate_version, Clap};
-use kvs::client::Client;
-use std::{net::SocketAddr, process};
+use kvs::{
+    auth,
+    client::Client,
+    error::{Error, ErrorKind, Result},
+    tls,
+};
+use std::{net::SocketAddr, path::PathBuf, process, sync::OnceLock};
 #[derive(Clap)]
 #[clap(version =crate_version!() , author = crate_authors!())]
 struct Options {
@@ -12,54 +17,229 @@ enum SubCommand {
     Get(Key),
     Set(KeyValue),
     RM(Key),
+    /// show connections and thread pool activity of server
+    Stats(Server),
+    /// copy store of server into an empty directory under its --backup-dir
+    Backup(Backup),
+    /// let a node join the raft cluster of server
+    AddMember(Member),
+    /// take a node out of the raft cluster of server
+    RemoveMember(Member),
 }
 #[derive(Clap)]
 struct Key {
     key: String,
-    #[clap(long, short, default_value = "127.0.0.1:4000")]
-    addr: SocketAddr,
+    #[clap(flatten)]
+    remote: Remote,
 }
 #[derive(Clap)]
 struct KeyValue {
     key: String,
     value: String,
+    #[clap(flatten)]
+    remote: Remote,
+}
+#[derive(Clap)]
+struct Backup {
+    /// directory on server, relative to its --backup-dir
+    dest: String,
+    #[clap(flatten)]
+    remote: Remote,
+}
+#[derive(Clap)]
+struct Member {
+    member: SocketAddr,
+    #[clap(flatten)]
+    remote: Remote,
+}
+#[derive(Clap)]
+struct Server {
+    #[clap(flatten)]
+    remote: Remote,
+}
+// where and how to reach the server
+#[derive(Clap)]
+struct Remote {
     #[clap(long, short, default_value = "127.0.0.1:4000")]
     addr: SocketAddr,
+    /// connect to a unix domain socket instead of `addr`
+    #[clap(long, parse(from_os_str))]
+    unix: Option<PathBuf>,
+    /// connect with tls, trusting certificates signed by this CA
+    #[clap(long, parse(from_os_str))]
+    ca_file: Option<PathBuf>,
+    /// name the server certificate is issued for
+    #[clap(long, default_value = "localhost")]
+    server_name: String,
+    /// certificate presented to servers requiring client authentication
+    #[clap(long, parse(from_os_str), requires = "tls-key")]
+    tls_cert: Option<PathBuf>,
+    #[clap(long, parse(from_os_str), requires = "tls-cert")]
+    tls_key: Option<PathBuf>,
+    /// log in as this user, with password from --password-file or KVS_PASSWORD
+    #[clap(long, short)]
+    user: Option<String>,
+    /// file holding password of --user, - reads it from stdin
+    #[clap(long, parse(from_os_str), requires = "user")]
+    password_file: Option<PathBuf>,
 }
 fn main() {
     let opts = Options::parse();
     match opts.subcmd {
-        SubCommand::Get(m) => {
-            let mut client = Client::connect(m.addr).expect("cannot connect to server");
-            match client.get(m.key) {
-                Ok(Some(value)) => {
-                    println!("{}", value);
-                }
-                _ => {
-                    println!("Key not found");
-                    process::exit(0);
-                }
+        SubCommand::Get(m) => match on_leader(&m.remote, |client| client.get(m.key.clone())) {
+            Ok(Some(value)) => {
+                println!("{}", value);
             }
-        }
+            Ok(None) => {
+                println!("Key not found");
+            }
+            Err(e) => fail(&e),
+        },
         SubCommand::RM(m) => {
-            let mut client = Client::connect(m.addr).expect("cannot connect to server");
-            match client.remove(m.key) {
-                Ok(_) => {}
-                _ => {
-                    eprintln!("Key not found");
-                    process::exit(-1);
-                }
+            if let Err(e) = on_leader(&m.remote, |client| client.remove(m.key.clone())) {
+                fail(&e);
             }
         }
         SubCommand::Set(m) => {
-            let mut client = Client::connect(m.addr).expect("cannot connect to server");
-            match client.set(m.key, m.value) {
-                Ok(_) => {}
-                Err(e) => {
-                    println!("{}", e);
-                    process::exit(-1);
+            let result = on_leader(&m.remote, |client| {
+                client.set(m.key.clone(), m.value.clone())
+            });
+            if let Err(e) = result {
+                fail(&e);
+            }
+        }
+        SubCommand::Backup(m) => {
+            let mut client = match connect(&m.remote, m.remote.addr) {
+                Ok(client) => client,
+                Err(e) => fail(&e),
+            };
+            match client.backup(m.dest) {
+                Ok(manifest) => println!(
+                    "copied {} files, up to write {}",
+                    manifest.files.len(),
+                    manifest.seq
+                ),
+                Err(e) => fail(&e),
+            }
+        }
+        SubCommand::AddMember(m) => {
+            let member = m.member.to_string();
+            match on_leader(&m.remote, |client| client.add_member(member.clone())) {
+                Ok(members) => println!("members: {}", members.join(" ")),
+                Err(e) => fail(&e),
+            }
+        }
+        SubCommand::RemoveMember(m) => {
+            let member = m.member.to_string();
+            match on_leader(&m.remote, |client| client.remove_member(member.clone())) {
+                Ok(members) => println!("members: {}", members.join(" ")),
+                Err(e) => fail(&e),
+            }
+        }
+        SubCommand::Stats(m) => {
+            let mut client = match connect(&m.remote, m.remote.addr) {
+                Ok(client) => client,
+                Err(e) => fail(&e),
+            };
+            match client.stats() {
+                Ok(stats) => {
+                    println!("connections: {}", stats.connections);
+                    println!("workers: {}", stats.pool.workers);
+                    println!("queued: {}", stats.pool.queued);
+                    println!("busy: {}", stats.pool.busy);
+                    println!("idle: {}", stats.pool.idle);
+                    println!("executed: {}", stats.pool.executed);
+                    println!("panics: {}", stats.pool.panics);
+                    println!("respawns: {}", stats.pool.respawns);
+                    println!("expired: {}", stats.pool.expired);
                 }
+                Err(e) => fail(&e),
             }
         }
     }
 }
+
+// run `request`, following redirects of raft nodes to their leader
+fn on_leader<T, F>(remote: &Remote, mut request: F) -> Result<T>
+where
+    F: FnMut(&mut Client) -> Result<T>,
+{
+    let mut addr = remote.addr;
+    // a node may point at a leader which just lost an election
+    for _ in 0..3 {
+        let result = connect(remote, addr).and_then(|mut client| request(&mut client));
+        match &result {
+            Err(e) => match e.kind() {
+                ErrorKind::NotLeader(_, Some(leader)) if remote.unix.is_none() => {
+                    match leader.parse() {
+                        Ok(leader) => addr = leader,
+                        Err(_) => return result,
+                    }
+                }
+                _ => return result,
+            },
+            Ok(_) => return result,
+        }
+    }
+    connect(remote, addr).and_then(|mut client| request(&mut client))
+}
+
+fn connect<'a>(remote: &Remote, addr: SocketAddr) -> Result<Client<'a>> {
+    let client = match (&remote.unix, &remote.ca_file) {
+        #[cfg(unix)]
+        (Some(path), _) => Client::connect_unix(path),
+        #[cfg(not(unix))]
+        (Some(_), _) => panic!("unix domain sockets are not supported on this platform"),
+        (None, Some(ca_file)) => {
+            let identity = remote.tls_cert.as_deref().zip(remote.tls_key.as_deref());
+            tls::client_config(ca_file, identity)
+                .and_then(|config| Client::connect_tls(addr, &remote.server_name, config))
+        }
+        (None, None) => Client::connect(addr),
+    };
+    client.and_then(|mut client| {
+        if let Some(user) = &remote.user {
+            client.auth(user.to_owned(), password(remote)?)?;
+        }
+        Ok(client)
+    })
+}
+
+// password of --user, read once as stdin can not be read again on redirects
+fn password(remote: &Remote) -> Result<String> {
+    static PASSWORD: OnceLock<String> = OnceLock::new();
+    if let Some(password) = PASSWORD.get() {
+        return Ok(password.clone());
+    }
+    let password = auth::read_password(remote.password_file.as_deref(), "KVS_PASSWORD")?
+        .ok_or_else(|| {
+            Error::invalid_command("--user needs --password-file or KVS_PASSWORD".to_string())
+        })?;
+    Ok(PASSWORD.get_or_init(|| password).clone())
+}
+
+// print error and exit with a status code telling what went wrong
+fn fail(err: &Error) -> ! {
+    match err.kind() {
+        ErrorKind::KeyNotFound(_) => eprintln!("Key not found"),
+        _ => eprintln!("{}", err),
+    }
+    process::exit(exit_code(err));
+}
+
+fn exit_code(err: &Error) -> i32 {
+    match err.kind() {
+        ErrorKind::KeyNotFound(_) => 2,
+        ErrorKind::InvalidCommand(_) => 3,
+        ErrorKind::Storage(_) => 4,
+        ErrorKind::ReadOnly(_) => 5,
+        ErrorKind::Overloaded(_) => 6,
+        ErrorKind::IO(_) => 7,
+        ErrorKind::UnsupportedVersion(_) => 8,
+        ErrorKind::Tls(_) => 9,
+        ErrorKind::Unauthenticated(_) => 10,
+        ErrorKind::PermissionDenied(_) => 11,
+        ErrorKind::NotLeader(..) => 12,
+        _ => 1,
+    }
+}
@@ -1,23 +1,296 @@
 use clap::{crate_authors, crate_version, Clap, Error, ErrorKind};
 use kvs::{
+    auth::{self, Users},
+    common::KvsEngine,
     error::Result,
-    kvs_store::KvStore,
+    kvs_store::{Durability, KvStore},
+    manifest::Manifest,
+    raft::RaftNode,
+    replication::Follower,
     server::Server,
-    thread_pool::{QueueThreadPool, ThreadPool},
+    thread_pool::{
+        NaiveThreadPool, QueueThreadPool, RayonThreadPool, StealingThreadPool, ThreadPool,
+    },
+    tls,
 };
+use serde::{Deserialize, Serialize, Serializer};
 use slog::*;
-use std::{env::current_dir, fs, net::SocketAddr, path::Path, process::exit, str::FromStr};
+use std::{
+    fmt::Display,
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    process::exit,
+    str::FromStr,
+    time::Duration,
+};
 
 #[derive(Clap)]
 #[clap(version =crate_version!() , author = crate_authors!())]
 struct Options {
-    #[clap(long, short, default_value = "127.0.0.1:4000")]
-    addr: SocketAddr,
+    /// read settings from this toml file, flags given override them
+    #[clap(long, parse(from_os_str))]
+    config: Option<PathBuf>,
+
+    /// print settings in effect as toml, then exit
+    #[clap(long)]
+    print_config: bool,
+
+    /// address to listen on [default: 127.0.0.1:4000]
+    #[clap(long, short)]
+    addr: Option<SocketAddr>,
+
+    /// directory of the store [default: db]
+    #[clap(long, parse(from_os_str))]
+    data_dir: Option<PathBuf>,
+
+    /// listen on this unix domain socket instead of tcp
+    #[clap(long, parse(from_os_str))]
+    unix: Option<PathBuf>,
+
+    /// kvs or sled [default: kvs]
+    #[clap(short, long)]
+    engine: Option<Engine>,
+
+    /// number of worker threads [default: 10]
+    #[clap(long)]
+    threads: Option<usize>,
+
+    /// let queue pool start up to this many threads while clients wait, defaults to --threads
+    #[clap(long)]
+    max_threads: Option<usize>,
+
+    /// seconds a thread above --threads stays idle before it exits [default: 60]
+    #[clap(long)]
+    thread_keep_alive: Option<u64>,
+
+    /// thread pool running client connections: naive, queue (or shared), rayon or stealing
+    /// [default: queue]
+    #[clap(long)]
+    pool: Option<Pool>,
+
+    /// stale bytes in db files before they are compacted [default: 8388608]
+    #[clap(long)]
+    compact_threshold: Option<u64>,
+
+    /// flush to hand writes to the OS, sync to fsync each write before answering
+    /// [default: flush]
+    #[clap(long)]
+    durability: Option<Durability>,
+
+    /// least important messages logged: critical, error, warn, info, debug or trace
+    /// [default: info]
+    #[clap(long)]
+    log_level: Option<String>,
+
+    /// serve tls with this certificate chain
+    #[clap(long, parse(from_os_str), requires = "tls-key")]
+    tls_cert: Option<PathBuf>,
+
+    #[clap(long, parse(from_os_str), requires = "tls-cert")]
+    tls_key: Option<PathBuf>,
+
+    /// require client certificates signed by this CA
+    #[clap(long, parse(from_os_str), requires = "tls-cert")]
+    tls_client_ca: Option<PathBuf>,
+
+    /// require clients to log in as one of the users in this file
+    #[clap(long, parse(from_os_str))]
+    users: Option<PathBuf>,
+
+    /// let clients back up the store into directories under this one,
+    /// backups are refused without it
+    #[clap(long, parse(from_os_str))]
+    backup_dir: Option<PathBuf>,
+
+    /// seconds to wait for in-flight requests when asked to stop [default: 30]
+    #[clap(long)]
+    shutdown_timeout: Option<u64>,
+
+    /// turn away clients while this many are connected [default: 1024]
+    #[clap(long)]
+    max_connections: Option<usize>,
 
-    #[clap(short, long, default_value = "kvs")]
+    /// seconds a client may wait before sending next request, 0 waits forever
+    /// [default: 300]
+    #[clap(long)]
+    idle_timeout: Option<u64>,
+
+    /// largest request in bytes [default: 16777216]
+    #[clap(long)]
+    max_request_size: Option<usize>,
+
+    /// connections waiting for a worker thread before new ones are turned away
+    /// [default: 1024]
+    #[clap(long)]
+    queue_size: Option<usize>,
+
+    /// follow the leader at this address, refusing writes from clients
+    #[clap(long)]
+    replica_of: Option<SocketAddr>,
+
+    /// log in to leader as this user, who must be allowed to read every key,
+    /// with password from --replica-password-file or KVS_REPLICA_PASSWORD
+    #[clap(long, requires = "replica-of")]
+    replica_user: Option<String>,
+
+    /// file holding password of --replica-user
+    #[clap(long, parse(from_os_str), requires = "replica-user")]
+    replica_password_file: Option<PathBuf>,
+
+    /// run as a member of a raft cluster, known to other members by --addr
+    #[clap(long, conflicts_with = "replica-of")]
+    raft: bool,
+
+    /// members a new raft cluster starts with, including this one.
+    /// Leave out to wait until a leader adds this node.
+    #[clap(long, requires = "raft", use_delimiter = true)]
+    members: Vec<SocketAddr>,
+
+    /// log in to other members as this user, who must be allowed to write every key,
+    /// with password from --raft-password-file or KVS_RAFT_PASSWORD
+    #[clap(long, requires = "raft")]
+    raft_user: Option<String>,
+
+    /// file holding password of --raft-user
+    #[clap(long, parse(from_os_str), requires = "raft-user")]
+    raft_password_file: Option<PathBuf>,
+
+    /// print hash of a password read from stdin to put in users file, then exit
+    #[clap(long)]
+    hash_password: bool,
+}
+
+/// Settings of a --config file, named like the flags setting them
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct Config {
+    addr: Option<SocketAddr>,
+    data_dir: Option<PathBuf>,
+    engine: Option<String>,
+    threads: Option<usize>,
+    max_threads: Option<usize>,
+    thread_keep_alive: Option<u64>,
+    pool: Option<String>,
+    compact_threshold: Option<u64>,
+    durability: Option<String>,
+    log_level: Option<String>,
+    shutdown_timeout: Option<u64>,
+    max_connections: Option<usize>,
+    idle_timeout: Option<u64>,
+    max_request_size: Option<usize>,
+    queue_size: Option<usize>,
+}
+
+/// Settings in effect, taken from flags, then config file, then defaults
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Settings {
+    addr: SocketAddr,
+    data_dir: PathBuf,
+    #[serde(serialize_with = "display")]
     engine: Engine,
+    threads: usize,
+    max_threads: usize,
+    thread_keep_alive: u64,
+    #[serde(serialize_with = "display")]
+    pool: Pool,
+    compact_threshold: u64,
+    #[serde(serialize_with = "display")]
+    durability: Durability,
+    #[serde(serialize_with = "level")]
+    log_level: Level,
+    shutdown_timeout: u64,
+    max_connections: usize,
+    idle_timeout: u64,
+    max_request_size: usize,
+    queue_size: usize,
+}
+
+impl Settings {
+    fn new(options: &Options) -> Result<Settings> {
+        let config: Config = match &options.config {
+            Some(path) => toml::from_str(&fs::read_to_string(path)?)
+                .map_err(|e| format!("{}: {}", path.display(), e))?,
+            None => Config::default(),
+        };
+        let threads = options.threads.or(config.threads).unwrap_or(10);
+        let log_level = options.log_level.as_ref().or(config.log_level.as_ref());
+        let log_level = match log_level {
+            Some(level) => level.parse().map_err(|_| {
+                "log level should be one of critical, error, warn, info, debug or trace".to_string()
+            })?,
+            None => Level::Info,
+        };
+        Ok(Settings {
+            addr: options
+                .addr
+                .or(config.addr)
+                .unwrap_or_else(|| SocketAddr::from(([127, 0, 0, 1], 4000))),
+            data_dir: options
+                .data_dir
+                .clone()
+                .or(config.data_dir)
+                .unwrap_or_else(|| PathBuf::from("db")),
+            engine: pick(options.engine, config.engine, Engine::Kvs)?,
+            threads,
+            max_threads: options
+                .max_threads
+                .or(config.max_threads)
+                .unwrap_or(threads),
+            thread_keep_alive: options
+                .thread_keep_alive
+                .or(config.thread_keep_alive)
+                .unwrap_or(60),
+            pool: pick(options.pool, config.pool, Pool::Queue)?,
+            compact_threshold: options
+                .compact_threshold
+                .or(config.compact_threshold)
+                .unwrap_or(KvStore::COMPACT_THRESHOLD),
+            durability: pick(options.durability, config.durability, Durability::Flush)?,
+            log_level,
+            shutdown_timeout: options
+                .shutdown_timeout
+                .or(config.shutdown_timeout)
+                .unwrap_or(30),
+            max_connections: options
+                .max_connections
+                .or(config.max_connections)
+                .unwrap_or(1024),
+            idle_timeout: options.idle_timeout.or(config.idle_timeout).unwrap_or(300),
+            max_request_size: options
+                .max_request_size
+                .or(config.max_request_size)
+                .unwrap_or(16 * 1024 * 1024),
+            queue_size: options.queue_size.or(config.queue_size).unwrap_or(1024),
+        })
+    }
+}
+
+// value of a flag if given, otherwise parsed from config file
+fn pick<T: FromStr>(flag: Option<T>, file: Option<String>, default: T) -> Result<T>
+where
+    T::Err: Display,
+{
+    match (flag, file) {
+        (Some(value), _) => Ok(value),
+        (None, Some(value)) => Ok(value.parse().map_err(|e: T::Err| e.to_string())?),
+        (None, None) => Ok(default),
+    }
 }
-#[derive(Debug, PartialEq, Eq)]
+
+fn display<T: Display, S: Serializer>(
+    value: &T,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.collect_str(value)
+}
+
+fn level<S: Serializer>(level: &Level, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_str(&level.as_str().to_lowercase())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum Engine {
     Kvs,
     Sled,
@@ -36,81 +309,269 @@ impl FromStr for Engine {
         }
     }
 }
-impl Engine {
-    fn to_string(&self) -> String {
+
+impl std::fmt::Display for Engine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Engine::Kvs => "kvs".to_string(),
-            Engine::Sled => "sled".to_string(),
+            Engine::Kvs => write!(f, "kvs"),
+            Engine::Sled => write!(f, "sled"),
         }
     }
 }
-// impl Display for Engine {
-//     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-//         match self {
-//             Engine::Kvs => "kvs".to_string(),
-//             Engine::Sled => "sled".to_string(),
-//         }
-//     }
-// }
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Pool {
+    Naive,
+    Queue,
+    Rayon,
+    Stealing,
+}
+
+impl FromStr for Pool {
+    type Err = Error;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "naive" => Ok(Pool::Naive),
+            "queue" | "shared" => Ok(Pool::Queue),
+            "rayon" => Ok(Pool::Rayon),
+            "stealing" => Ok(Pool::Stealing),
+            _ => Err(Error::with_description(
+                "pool should be one of naive, queue, shared, rayon or stealing".to_string(),
+                ErrorKind::InvalidValue,
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for Pool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Pool::Naive => write!(f, "naive"),
+            Pool::Queue => write!(f, "queue"),
+            Pool::Rayon => write!(f, "rayon"),
+            Pool::Stealing => write!(f, "stealing"),
+        }
+    }
+}
+
 fn main() {
-    let logger = logger();
     let options = Options::parse();
-    let addr = options.addr;
-    let engine = options.engine;
-    let res = current_engine(&logger).and_then(|e| {
+    if options.hash_password {
+        match auth::read_password(Some(Path::new("-")), "") {
+            Ok(Some(password)) => println!("{}", auth::hash_password(&password)),
+            _ => {
+                eprintln!("unable to read password from stdin");
+                exit(1);
+            }
+        }
+        return;
+    }
+    let settings = match Settings::new(&options) {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("invalid settings: {}", e);
+            exit(1);
+        }
+    };
+    if options.print_config {
+        match toml::to_string(&settings) {
+            Ok(config) => print!("{}", config),
+            Err(e) => {
+                eprintln!("{}", e);
+                exit(1);
+            }
+        }
+        return;
+    }
+    let logger = logger(settings.log_level);
+    let res = current_engine(&settings.data_dir, &logger).and_then(|e| {
         // not target engine
-        if e.is_some() && engine != e.unwrap() {
+        if e.is_some() && settings.engine != e.unwrap() {
             error!(&logger, "Wrong engine!");
             exit(1);
         }
-        run(&engine, &addr, logger)
+        run(&options, &settings, logger)
     });
 
-    if res.is_err() {
-        println!("server failed {:?}", res);
+    if let Err(e) = res {
+        println!("server failed: {}", e);
         exit(1);
     }
 }
 
-fn logger() -> slog::Logger {
+fn logger(level: Level) -> slog::Logger {
     let decorator = slog_term::TermDecorator::new().build();
     let drain = slog_term::FullFormat::new(decorator).build().fuse();
     let drain = slog_async::Async::new(drain).build().fuse();
+    let drain = drain.filter_level(level).fuse();
 
     slog::Logger::root(drain, o!())
 }
 
-fn run(engine: &Engine, addr: &SocketAddr, logger: Logger) -> Result<()> {
+fn run(options: &Options, settings: &Settings, logger: Logger) -> Result<()> {
+    let engine = &settings.engine;
+    let addr = &settings.addr;
     info!(logger, "YaKvs initializing";
         "version" => crate_version!(),
         "engine" => engine.to_string(),
-         "ip" => addr
+         "ip" => addr,
+         "unix" => options.unix.as_ref().map(|path| path.display().to_string()),
+         "tls" => options.tls_cert.is_some(),
+         "pool" => settings.pool.to_string(),
+         "replica_of" => options.replica_of.map(|leader| leader.to_string()),
+         "raft" => options.raft,
+         "threads" => settings.threads,
+         "data_dir" => settings.data_dir.display().to_string(),
+         "durability" => settings.durability.to_string()
     );
-    let current_dir = current_dir()?;
-    let current_dir = current_dir.join("./db");
-    fs::create_dir_all(&current_dir)?;
-    fs::write(current_dir.join("engine"), engine.to_string())?;
+    let path = settings.data_dir.as_path();
+    fs::create_dir_all(path)?;
+    fs::write(path.join("engine"), engine.to_string())?;
 
     match engine {
         Engine::Kvs => {
-            let path = Path::new(&current_dir);
-            let store = KvStore::open(path)?;
-            let thread_pool = QueueThreadPool::new(10)?;
-            let mut server = Server::new(store, thread_pool);
-            server.serve(addr, logger)?;
-            Ok(())
+            let store = KvStore::open(path)?
+                .with_compact_threshold(settings.compact_threshold)
+                .with_durability(settings.durability);
+            let raft = if options.raft {
+                let node = RaftNode::open(
+                    settings.addr,
+                    &path.join("raft"),
+                    store.clone(),
+                    &options.members,
+                )?;
+                Some(match &options.raft_user {
+                    Some(user) => node.with_auth(
+                        user.clone(),
+                        login_password(&options.raft_password_file, "KVS_RAFT_PASSWORD")?,
+                    ),
+                    None => node,
+                })
+            } else {
+                None
+            };
+            let threads = settings.threads;
+            match settings.pool {
+                Pool::Naive => serve(
+                    options,
+                    settings,
+                    store,
+                    raft,
+                    NaiveThreadPool::new(threads)?,
+                    logger,
+                ),
+                Pool::Queue => {
+                    let panic_logger = logger.clone();
+                    let pool = QueueThreadPool::bounded(threads, settings.queue_size)?
+                        .with_max_workers(settings.max_threads)
+                        .with_keep_alive(Duration::from_secs(settings.thread_keep_alive))
+                        .with_panic_handler(move |panic| {
+                            error!(panic_logger, "job panicked";
+                                "job" => panic.job,
+                                "worker" => panic.worker,
+                                "message" => panic.message().unwrap_or("unknown"));
+                        });
+                    serve(options, settings, store, raft, pool, logger)
+                }
+                Pool::Rayon => serve(
+                    options,
+                    settings,
+                    store,
+                    raft,
+                    RayonThreadPool::new(threads)?,
+                    logger,
+                ),
+                Pool::Stealing => serve(
+                    options,
+                    settings,
+                    store,
+                    raft,
+                    StealingThreadPool::new(threads)?,
+                    logger,
+                ),
+            }
         }
         Engine::Sled => Ok(()),
     }
 }
 
-fn current_engine(logger: &Logger) -> Result<Option<Engine>> {
-    let path = current_dir()?.join("./db/engine");
-    if !path.exists() {
-        return Ok(None);
+fn serve<E: KvsEngine, P: ThreadPool + Send + Sync + 'static>(
+    options: &Options,
+    settings: &Settings,
+    engine: E,
+    raft: Option<RaftNode<E>>,
+    pool: P,
+    logger: Logger,
+) -> Result<()> {
+    let follower = match options.replica_of {
+        Some(leader) => {
+            let follower = Follower::new(engine.clone(), leader);
+            Some(match &options.replica_user {
+                Some(user) => follower.with_auth(
+                    user.clone(),
+                    login_password(&options.replica_password_file, "KVS_REPLICA_PASSWORD")?,
+                ),
+                None => follower,
+            })
+        }
+        None => None,
+    };
+    let mut server = Server::new(engine, pool)
+        .with_max_connections(settings.max_connections)
+        .with_max_request_size(settings.max_request_size);
+    if settings.idle_timeout > 0 {
+        server = server.with_idle_timeout(Duration::from_secs(settings.idle_timeout));
+    }
+    if let (Some(cert), Some(key)) = (&options.tls_cert, &options.tls_key) {
+        let config = tls::server_config(cert, key, options.tls_client_ca.as_deref())?;
+        server = server.with_tls(config);
+    }
+    if let Some(path) = &options.users {
+        server = server.with_users(Users::load(path)?);
+    }
+    if let Some(dir) = &options.backup_dir {
+        server = server.with_backup_dir(dir.clone());
+    }
+    server = server.with_shutdown_timeout(Duration::from_secs(settings.shutdown_timeout));
+    if let Some(follower) = follower {
+        server = server.with_follower(follower);
+    }
+    if let Some(raft) = raft {
+        server = server.with_raft(raft);
+    }
+
+    // stop gracefully on SIGINT and SIGTERM
+    let handle = server.shutdown_handle();
+    let signal_logger = logger.clone();
+    ctrlc::set_handler(move || {
+        info!(signal_logger, "signal received, stopping");
+        handle.shutdown();
+    })
+    .map_err(|e| e.to_string())?;
+    match &options.unix {
+        #[cfg(unix)]
+        Some(path) => server.serve_unix(path, logger),
+        #[cfg(not(unix))]
+        Some(_) => panic!("unix domain sockets are not supported on this platform"),
+        None => server.serve(&settings.addr, logger),
     }
+}
+
+// password for logging in to other servers, kept off the command line
+fn login_password(file: &Option<PathBuf>, var: &str) -> Result<String> {
+    auth::read_password(file.as_deref(), var)?.ok_or_else(|| {
+        kvs::error::Error::invalid_command(format!("no password given in a file or {}", var))
+    })
+}
+
+fn current_engine(data_dir: &Path, logger: &Logger) -> Result<Option<Engine>> {
+    // engine file is left for stores without manifest
+    let name = match Manifest::load(data_dir)? {
+        Some(manifest) => manifest.engine,
+        None if data_dir.join("engine").exists() => fs::read_to_string(data_dir.join("engine"))?,
+        None => return Ok(None),
+    };
 
-    match fs::read_to_string(path)?.parse() {
+    match name.parse() {
         Ok(engine) => Ok(Some(engine)),
         Err(_) => {
             warn!(logger, "unable to read engine");
@@ -0,0 +1,70 @@
+use clap::{crate_authors, crate_version, Clap};
+use kvs::{
+    auth,
+    error::{Error, Result},
+    shard::{ShardedClient, DEFAULT_VNODES},
+};
+use std::{net::SocketAddr, path::PathBuf, process};
+
+/// Move keys between servers sharing keys by consistent hashing
+#[derive(Clap)]
+#[clap(version =crate_version!() , author = crate_authors!())]
+struct Options {
+    #[clap(subcommand)]
+    subcmd: SubCommand,
+}
+#[derive(Clap)]
+enum SubCommand {
+    /// move keys the new node owns to it
+    Add(Change),
+    /// move keys of a node to the others, so it can be taken down
+    Remove(Change),
+}
+#[derive(Clap)]
+struct Change {
+    node: SocketAddr,
+    /// nodes keys are spread over now: without the node when adding it,
+    /// including it when removing it
+    #[clap(long, use_delimiter = true, required = true)]
+    nodes: Vec<SocketAddr>,
+    /// points each node gets on the ring, as used by clients
+    #[clap(long, default_value = "160")]
+    vnodes: usize,
+    /// log in as this user, who must be allowed to write every key,
+    /// with password from --password-file or KVS_PASSWORD
+    #[clap(long, short)]
+    user: Option<String>,
+    /// file holding password of --user, - reads it from stdin
+    #[clap(long, parse(from_os_str), requires = "user")]
+    password_file: Option<PathBuf>,
+}
+
+fn main() {
+    let opts = Options::parse();
+    let result = match opts.subcmd {
+        SubCommand::Add(change) => client(&change).and_then(|mut c| c.add_node(change.node)),
+        SubCommand::Remove(change) => client(&change).and_then(|mut c| c.remove_node(change.node)),
+    };
+    match result {
+        Ok(moved) => println!("moved {} keys", moved),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
+
+fn client<'a>(change: &Change) -> Result<ShardedClient<'a>> {
+    let mut client = ShardedClient::new(&change.nodes);
+    if change.vnodes != DEFAULT_VNODES {
+        client = client.with_vnodes(change.vnodes);
+    }
+    if let Some(user) = &change.user {
+        let password = auth::read_password(change.password_file.as_deref(), "KVS_PASSWORD")?
+            .ok_or_else(|| {
+                Error::invalid_command("--user needs --password-file or KVS_PASSWORD".to_string())
+            })?;
+        client = client.with_auth(user.clone(), password);
+    }
+    Ok(client)
+}
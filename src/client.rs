@@ -1,75 +1,310 @@
 use std::{
-    io::{BufReader, BufWriter, Write},
+    io::{self, BufReader, BufWriter, Write},
     net::{SocketAddr, TcpStream},
+    sync::Arc,
+    time::Duration,
 };
 
+#[cfg(unix)]
+use std::{os::unix::net::UnixStream, path::Path};
+
+use rustls::ClientConfig;
+
 use serde_json::{de::IoRead, StreamDeserializer};
 
 use crate::{
+    backup::Manifest,
     error::{Error, ErrorKind, Result},
-    net::{Request, Response},
+    net::{Handshake, Replication, Request, Response, FEATURES, PROTOCOL_VERSION},
+    raft::RaftMessage,
+    server::ServerStats,
+    stream::Stream,
 };
 
 pub struct Client<'a> {
-    writer: BufWriter<TcpStream>,
-    reader: StreamDeserializer<'a, IoRead<BufReader<TcpStream>>, Response>,
+    writer: BufWriter<Stream>,
+    reader: StreamDeserializer<'a, IoRead<BufReader<Stream>>, Response>,
+    handshake: Handshake,
 }
 
 impl<'a> Client<'a> {
     pub fn connect(addr: SocketAddr) -> Result<Client<'a>> {
         let stream = TcpStream::connect(addr)?;
+        Client::new(Stream::Tcp(stream))
+    }
+
+    /// connect giving up after `timeout`, which also limits how long to wait for each response
+    pub fn connect_timeout(addr: SocketAddr, timeout: Duration) -> Result<Client<'a>> {
+        let stream = TcpStream::connect_timeout(&addr, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        Client::new(Stream::Tcp(stream))
+    }
+
+    /// connect to a tls server, whose certificate must be issued for `server_name`
+    pub fn connect_tls(
+        addr: SocketAddr,
+        server_name: &str,
+        config: Arc<ClientConfig>,
+    ) -> Result<Client<'a>> {
+        let stream = TcpStream::connect(addr)?;
+        Client::new(Stream::tls_client(stream, server_name, config)?)
+    }
+
+    /// connect to a server listening on unix domain socket at `path`
+    #[cfg(unix)]
+    pub fn connect_unix(path: &Path) -> Result<Client<'a>> {
+        let stream = UnixStream::connect(path)?;
+        Client::new(Stream::Unix(stream))
+    }
+
+    fn new(stream: Stream) -> Result<Client<'a>> {
         let writer = BufWriter::new(stream.try_clone()?);
         let reader = BufReader::new(stream);
         let reader = serde_json::Deserializer::from_reader(reader).into_iter::<Response>();
-        Ok(Client { reader, writer })
+        let mut client = Client {
+            reader,
+            writer,
+            handshake: Handshake {
+                version: PROTOCOL_VERSION,
+                features: vec![],
+            },
+        };
+        client.handshake()?;
+        Ok(client)
     }
 
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        let request = Request::Get {
-            key: key.to_owned(),
+    // agree on protocol version and features with server
+    fn handshake(&mut self) -> Result<()> {
+        let request = Request::Handshake {
+            version: PROTOCOL_VERSION,
+            features: FEATURES.iter().map(|f| f.to_string()).collect(),
         };
-
         self.send_request(&request)?;
 
-        if let Some(response) = self.reader.next() {
-            if let Response::Get(Ok(result)) = response? {
-                return Ok(result);
+        match self.receive()? {
+            Response::Handshake(result) => {
+                self.handshake = result?;
+                Ok(())
             }
+            response => Err(unexpected_response(&response)),
+        }
+    }
+
+    /// log in as `user`, required by servers having a users file
+    pub fn auth(&mut self, user: String, password: String) -> Result<()> {
+        self.send_request(&Request::Auth { user, password })?;
+
+        match self.receive()? {
+            Response::Auth(result) => Ok(result?),
+            response => Err(unexpected_response(&response)),
+        }
+    }
+
+    /// what server and its thread pool are doing
+    pub fn stats(&mut self) -> Result<ServerStats> {
+        if !self.supports("stats") {
+            return Err(Error::from(ErrorKind::UnsupportedVersion(
+                "server does not report stats".to_string(),
+            )));
+        }
+        self.send_request(&Request::Stats {})?;
+
+        match self.receive()? {
+            Response::Stats(result) => Ok(result?),
+            response => Err(unexpected_response(&response)),
+        }
+    }
+
+    /// up to `limit` pairs in key order, starting after key `after`.
+    /// Server may return fewer, an empty page means there are no more.
+    pub fn scan(&mut self, after: Option<String>, limit: usize) -> Result<Vec<(String, String)>> {
+        if !self.supports("scan") {
+            return Err(Error::from(ErrorKind::UnsupportedVersion(
+                "server does not support scan".to_string(),
+            )));
+        }
+        self.send_request(&Request::Scan { after, limit })?;
+
+        match self.receive()? {
+            Response::Scan(result) => Ok(result?),
+            response => Err(unexpected_response(&response)),
+        }
+    }
+
+    /// have server copy its store into empty directory `dest` on the server's disk
+    pub fn backup(&mut self, dest: String) -> Result<Manifest> {
+        if !self.supports("backup") {
+            return Err(Error::from(ErrorKind::UnsupportedVersion(
+                "server does not support backup".to_string(),
+            )));
+        }
+        self.send_request(&Request::Backup { dest })?;
+
+        match self.receive()? {
+            Response::Backup(result) => Ok(result?),
+            response => Err(unexpected_response(&response)),
+        }
+    }
+
+    /// writes server made after `seq`, or a snapshot when they are gone
+    pub fn replicate(&mut self, seq: u64) -> Result<Replication> {
+        if !self.supports("replication") {
+            return Err(Error::from(ErrorKind::UnsupportedVersion(
+                "server does not support replication".to_string(),
+            )));
+        }
+        self.send_request(&Request::Replicate { seq })?;
+
+        match self.receive()? {
+            Response::Replicate(result) => Ok(result?),
+            response => Err(unexpected_response(&response)),
+        }
+    }
+
+    /// send a message to another node of a raft cluster, returning its answer
+    pub fn raft(&mut self, message: RaftMessage) -> Result<RaftMessage> {
+        self.require_raft()?;
+        self.send_request(&Request::Raft { message })?;
+
+        match self.receive()? {
+            Response::Raft(result) => Ok(result?),
+            response => Err(unexpected_response(&response)),
+        }
+    }
+
+    /// let node at `addr` join the raft cluster led by server, returning its members
+    pub fn add_member(&mut self, addr: String) -> Result<Vec<String>> {
+        self.require_raft()?;
+        self.send_request(&Request::AddMember { addr })?;
+
+        match self.receive()? {
+            Response::Members(result) => Ok(result?),
+            response => Err(unexpected_response(&response)),
+        }
+    }
+
+    /// take node at `addr` out of the raft cluster led by server, returning its members
+    pub fn remove_member(&mut self, addr: String) -> Result<Vec<String>> {
+        self.require_raft()?;
+        self.send_request(&Request::RemoveMember { addr })?;
+
+        match self.receive()? {
+            Response::Members(result) => Ok(result?),
+            response => Err(unexpected_response(&response)),
         }
+    }
+
+    fn require_raft(&self) -> Result<()> {
+        if !self.supports("raft") {
+            return Err(Error::from(ErrorKind::UnsupportedVersion(
+                "server does not support raft".to_string(),
+            )));
+        }
+        Ok(())
+    }
 
-        Err(Error::from(ErrorKind::KeyNotFound(format!(
-            "cannot get value of key:{}",
-            key
-        ))))
+    /// protocol version agreed on with server
+    pub fn version(&self) -> u32 {
+        self.handshake.version
+    }
+
+    /// whether server agreed to use a feature
+    pub fn supports(&self, feature: &str) -> bool {
+        self.handshake.features.iter().any(|f| f == feature)
+    }
+
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.send_request(&Request::Get { key })?;
+
+        match self.receive()? {
+            Response::Get(result) => Ok(result?),
+            response => Err(unexpected_response(&response)),
+        }
     }
 
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        let request = Request::Set { key, value };
-        self.send_request(&request)?;
+        self.send_request(&Request::Set { key, value })?;
 
-        if let Some(response) = self.reader.next() {
-            if let Response::Set(Ok(())) = response? {
-                return Ok(());
-            }
+        match self.receive()? {
+            Response::Set(result) => Ok(result?),
+            response => Err(unexpected_response(&response)),
         }
-        Err(Error::from(ErrorKind::Error(
-            "cannot get response from server".to_string(),
-        )))
     }
 
     pub fn remove(&mut self, key: String) -> Result<()> {
-        let request = Request::Remove { key };
-        self.send_request(&request)?;
+        self.send_request(&Request::Remove { key })?;
+
+        match self.receive()? {
+            Response::Remove(result) => Ok(result?),
+            response => Err(unexpected_response(&response)),
+        }
+    }
+
+    /// get values of many keys in one round trip
+    pub fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        if !self.supports("multi") {
+            return keys.into_iter().map(|key| self.get(key)).collect();
+        }
+        self.send_request(&Request::GetMany { keys })?;
+
+        match self.receive()? {
+            Response::GetMany(result) => Ok(result?),
+            response => Err(unexpected_response(&response)),
+        }
+    }
+
+    /// set many pairs in one round trip
+    pub fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        if !self.supports("multi") {
+            for (key, value) in pairs {
+                self.set(key, value)?;
+            }
+            return Ok(());
+        }
+        self.send_request(&Request::SetMany { pairs })?;
+
+        match self.receive()? {
+            Response::SetMany(result) => Ok(result?),
+            response => Err(unexpected_response(&response)),
+        }
+    }
 
-        if let Some(response) = self.reader.next() {
-            if let Response::Remove(Ok(())) = response? {
-                return Ok(());
+    /// remove many keys in one round trip, telling which of them existed
+    pub fn remove_many(&mut self, keys: Vec<String>) -> Result<Vec<bool>> {
+        if !self.supports("multi") {
+            let mut removed = Vec::with_capacity(keys.len());
+            for key in keys {
+                match self.remove(key) {
+                    Ok(()) => removed.push(true),
+                    Err(e) => match e.kind() {
+                        ErrorKind::KeyNotFound(_) => removed.push(false),
+                        _ => return Err(e),
+                    },
+                }
             }
+            return Ok(removed);
+        }
+        self.send_request(&Request::RemoveMany { keys })?;
+
+        match self.receive()? {
+            Response::RemoveMany(result) => Ok(result?),
+            response => Err(unexpected_response(&response)),
         }
+    }
 
-        Err(Error::from(ErrorKind::Error(
-            "cannot get response from server".to_string(),
-        )))
+    // wait for next response from server
+    fn receive(&mut self) -> Result<Response> {
+        match self.reader.next() {
+            Some(response) => match response? {
+                Response::Error(err) => Err(Error::from(err)),
+                response => Ok(response),
+            },
+            None => Err(Error::from(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed by server",
+            ))),
+        }
     }
 
     fn send_request(&mut self, request: &Request) -> Result<()> {
@@ -81,3 +316,17 @@ impl<'a> Client<'a> {
         Ok(())
     }
 }
+
+impl<'a> Drop for Client<'a> {
+    fn drop(&mut self) {
+        // let server know the session ended on purpose
+        let _ = self.writer.get_ref().close();
+    }
+}
+
+fn unexpected_response(response: &Response) -> Error {
+    Error::from(ErrorKind::InvalidFormat(format!(
+        "unexpected response from server: {:?}",
+        response
+    )))
+}
@@ -1,17 +1,180 @@
-use crate::error::Result;
+use crate::backup::Manifest;
+use crate::error::{Error, ErrorKind, Result};
 use serde::{Deserialize, Serialize};
+use std::path::Path;
 
-#[derive(Debug, Serialize, Deserialize)]
+/// A record of the log. Writes are numbered in order by `seq`,
+/// records written before numbering existed have 0.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
-    Get { key: String },
+    Set {
+        key: String,
+        value: String,
+        #[serde(default)]
+        seq: u64,
+    },
+    Remove {
+        key: String,
+        #[serde(default)]
+        seq: u64,
+    },
+    Get {
+        key: String,
+    },
+    /// every write up to `seq` happened before this point,
+    /// records of some of them may be gone after compaction
+    Mark {
+        seq: u64,
+    },
+}
+
+impl Command {
+    pub fn seq(&self) -> u64 {
+        match self {
+            Command::Set { seq, .. } | Command::Remove { seq, .. } | Command::Mark { seq } => *seq,
+            Command::Get { .. } => 0,
+        }
+    }
+
+    /// same write numbered `seq`
+    pub fn with_seq(mut self, new_seq: u64) -> Self {
+        match &mut self {
+            Command::Set { seq, .. } | Command::Remove { seq, .. } | Command::Mark { seq } => {
+                *seq = new_seq
+            }
+            Command::Get { .. } => {}
+        }
+        self
+    }
 }
 
 pub trait KvsEngine: Clone + Send + 'static {
     fn get(&self, key: String) -> Result<Option<String>>;
     fn set(&self, key: String, value: String) -> Result<()>;
     fn remove(&self, key: String) -> Result<String>;
+
+    /// get values of many keys, `None` for keys not found
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        keys.into_iter().map(|key| self.get(key)).collect()
+    }
+
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        for (key, value) in pairs {
+            self.set(key, value)?;
+        }
+        Ok(())
+    }
+
+    /// remove many keys, telling which of them existed
+    fn remove_many(&self, keys: Vec<String>) -> Result<Vec<bool>> {
+        let mut removed = Vec::with_capacity(keys.len());
+        for key in keys {
+            match self.remove(key) {
+                Ok(_) => removed.push(true),
+                Err(e) => match e.kind() {
+                    ErrorKind::KeyNotFound(_) => removed.push(false),
+                    _ => return Err(e),
+                },
+            }
+        }
+        Ok(removed)
+    }
+
+    /// make sure everything written so far survives a crash
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// up to `limit` pairs in key order, starting after key `after`
+    fn scan(&self, _after: Option<String>, _limit: usize) -> Result<Vec<(String, String)>> {
+        Err(unsupported("scanning"))
+    }
+
+    /// every pair in key order, read `batch` at a time with `scan`
+    fn pairs(&self, batch: usize) -> Pairs<'_, Self> {
+        Pairs {
+            engine: self,
+            batch,
+            after: None,
+            page: Vec::new().into_iter(),
+            done: false,
+        }
+    }
+
+    /// copy everything stored so far into empty directory `dest`, while writes go on
+    fn backup(&self, _dest: &Path) -> Result<Manifest> {
+        Err(unsupported("backup"))
+    }
+
+    /// sequence number of the last write
+    fn last_seq(&self) -> u64 {
+        0
+    }
+
+    /// up to `limit` writes made after `seq`, in order.
+    /// `None` when some of them are gone and a snapshot is needed.
+    fn changes_since(&self, _seq: u64, _limit: usize) -> Result<Option<Vec<Command>>> {
+        Err(unsupported("replication"))
+    }
+
+    /// every pair stored, and the sequence number of the last write they include
+    fn snapshot(&self) -> Result<(u64, Vec<(String, String)>)> {
+        Err(unsupported("replication"))
+    }
+
+    /// replay writes from `changes_since` of another store
+    fn apply(&self, _commands: Vec<Command>) -> Result<()> {
+        Err(unsupported("replication"))
+    }
+
+    /// replace everything stored by a `snapshot` of another store
+    fn restore(&self, _seq: u64, _pairs: Vec<(String, String)>) -> Result<()> {
+        Err(unsupported("replication"))
+    }
+}
+
+/// Iterator over pairs of an engine, see `KvsEngine::pairs`.
+/// Pairs written while iterating may or may not be seen.
+pub struct Pairs<'a, T: KvsEngine> {
+    engine: &'a T,
+    batch: usize,
+    // last key of the page read before
+    after: Option<String>,
+    page: std::vec::IntoIter<(String, String)>,
+    done: bool,
+}
+
+impl<'a, T: KvsEngine> Iterator for Pairs<'a, T> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(pair) = self.page.next() {
+            return Some(Ok(pair));
+        }
+        if self.done {
+            return None;
+        }
+        match self.engine.scan(self.after.take(), self.batch) {
+            Ok(page) => {
+                // a short page is the last one
+                self.done = page.len() < self.batch;
+                self.after = page.last().map(|(key, _)| key.to_owned());
+                self.page = page.into_iter();
+                self.page.next().map(Ok)
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+fn unsupported(what: &str) -> Error {
+    Error::from(ErrorKind::Storage(format!(
+        "{} is not supported by this engine",
+        what
+    )))
 }
 
 pub trait DataBase {
@@ -19,7 +182,7 @@ pub trait DataBase {
     fn set(&mut self, key: String, value: String) -> Result<()>;
     fn remove(&mut self, key: String) -> Result<()>;
 }
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub struct OffSet {
     file_no: u64,
     start: u64,
@@ -43,6 +43,35 @@ pub enum ErrorKind {
 
     #[fail(display = "{}", _0)]
     ThreadPoolError(String),
+
+    #[fail(display = "{}", _0)]
+    Storage(String),
+
+    #[fail(display = "{}", _0)]
+    ReadOnly(String),
+
+    #[fail(display = "{}", _0)]
+    Overloaded(String),
+
+    #[fail(display = "{}", _0)]
+    UnsupportedVersion(String),
+
+    #[fail(display = "{}", _0)]
+    Tls(String),
+
+    #[fail(display = "{}", _0)]
+    Unauthenticated(String),
+
+    #[fail(display = "{}", _0)]
+    PermissionDenied(String),
+
+    /// store directory used by another process
+    #[fail(display = "{}", _0)]
+    Locked(String),
+
+    /// node of a raft cluster which does not lead it, with the leader if known
+    #[fail(display = "{}", _0)]
+    NotLeader(String, Option<String>),
 }
 impl Error {
     pub fn key_not_found(message: String) -> Self {
@@ -52,9 +81,22 @@ impl Error {
     pub fn invalid_command(message: String) -> Self {
         Error::from(ErrorKind::InvalidCommand(message))
     }
+
+    pub fn not_leader(leader: Option<String>) -> Self {
+        let message = match &leader {
+            Some(leader) => format!("node is not the leader, send requests to {}", leader),
+            None => "node is not the leader, no leader is known yet".to_string(),
+        };
+        Error::from(ErrorKind::NotLeader(message, leader))
+    }
+
     pub fn as_string(&self) -> String {
         format!("{}", self)
     }
+
+    pub fn kind(&self) -> &ErrorKind {
+        self.inner.get_context()
+    }
 }
 
 impl Display for Error {
@@ -128,3 +170,11 @@ impl<T> From<SendError<T>> for Error {
         }
     }
 }
+
+impl From<rustls::Error> for Error {
+    fn from(err: rustls::Error) -> Self {
+        Error {
+            inner: Context::new(ErrorKind::Tls(err.to_string())),
+        }
+    }
+}
@@ -0,0 +1,207 @@
+//! Live pairs of any engine written to or read from JSON Lines or CSV files
+use crate::common::KvsEngine;
+use crate::error::{Error, ErrorKind, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{BufRead, Write};
+use std::str::FromStr;
+
+// pairs read from engine or written to it at a time
+const BATCH: usize = 1024;
+
+/// File format of an export
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// one `{"key":..,"value":..}` object per line
+    Jsonl,
+    /// a `key,value` header, then one pair per record
+    Csv,
+}
+
+impl FromStr for Format {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "jsonl" => Ok(Format::Jsonl),
+            "csv" => Ok(Format::Csv),
+            _ => Err(Error::invalid_command(
+                "format should be either jsonl or csv".to_string(),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Format::Jsonl => write!(f, "jsonl"),
+            Format::Csv => write!(f, "csv"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Record {
+    key: String,
+    value: String,
+}
+
+/// write every live pair of `engine` in key order, returning how many were written
+pub fn export<T: KvsEngine, W: Write>(engine: &T, mut writer: W, format: Format) -> Result<usize> {
+    if format == Format::Csv {
+        writer.write_all(b"key,value\n")?;
+    }
+    let mut count = 0;
+    for pair in engine.pairs(BATCH) {
+        let (key, value) = pair?;
+        match format {
+            Format::Jsonl => {
+                serde_json::to_writer(&mut writer, &Record { key, value })?;
+                writer.write_all(b"\n")?;
+            }
+            Format::Csv => {
+                writeln!(writer, "{},{}", csv_field(&key), csv_field(&value))?;
+            }
+        }
+        count += 1;
+    }
+    writer.flush()?;
+    Ok(count)
+}
+
+/// Set every pair read from `reader` in `engine`, keeping pairs not in it.
+/// Pairs are set in batches and the engine is flushed at the end.
+/// Returns how many were read.
+pub fn import<T: KvsEngine, R: BufRead>(
+    engine: &T,
+    mut reader: R,
+    format: Format,
+) -> Result<usize> {
+    let mut count = 0;
+    let mut batch = Vec::with_capacity(BATCH);
+    let mut line = 0;
+    let mut buf = String::new();
+    loop {
+        buf.clear();
+        let start = line + 1;
+        line += read_record(&mut reader, &mut buf, format)?;
+        if buf.is_empty() {
+            break;
+        }
+        let record = buf.trim_end_matches(&['\n', '\r'][..]);
+        if record.trim().is_empty() {
+            continue;
+        }
+        match format {
+            Format::Jsonl => {
+                let record: Record =
+                    serde_json::from_str(record).map_err(|e| invalid(start, &e.to_string()))?;
+                batch.push((record.key, record.value));
+            }
+            Format::Csv if start == 1 => {
+                if record != "key,value" {
+                    return Err(invalid(start, "expected a key,value header"));
+                }
+                continue;
+            }
+            Format::Csv => match parse_csv(record) {
+                Some(mut fields) if fields.len() == 2 => {
+                    let value = fields.pop().unwrap();
+                    let key = fields.pop().unwrap();
+                    batch.push((key, value));
+                }
+                Some(fields) => {
+                    return Err(invalid(
+                        start,
+                        &format!("expected 2 fields, found {}", fields.len()),
+                    ))
+                }
+                None => return Err(invalid(start, "malformed quoted field")),
+            },
+        }
+        count += 1;
+        if batch.len() == BATCH {
+            engine.set_many(std::mem::replace(&mut batch, Vec::with_capacity(BATCH)))?;
+        }
+    }
+    if !batch.is_empty() {
+        engine.set_many(batch)?;
+    }
+    engine.flush()?;
+    Ok(count)
+}
+
+// read one record into `buf`, returning how many lines it took.
+// A csv record goes on over line breaks inside quotes.
+fn read_record<R: BufRead>(reader: &mut R, buf: &mut String, format: Format) -> Result<usize> {
+    let mut lines = 0;
+    loop {
+        if reader.read_line(buf)? == 0 {
+            return Ok(lines);
+        }
+        lines += 1;
+        // quotes come in pairs, escaped ones too
+        let open = format == Format::Csv && buf.matches('"').count() % 2 == 1;
+        if !open {
+            return Ok(lines);
+        }
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+// fields of a csv record, `None` when quotes are misplaced
+fn parse_csv(record: &str) -> Option<Vec<String>> {
+    let mut fields = Vec::new();
+    let mut chars = record.chars().peekable();
+    loop {
+        let mut field = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            loop {
+                match chars.next()? {
+                    '"' if chars.peek() == Some(&'"') => {
+                        chars.next();
+                        field.push('"');
+                    }
+                    '"' => break,
+                    c => field.push(c),
+                }
+            }
+            match chars.next() {
+                Some(',') => fields.push(field),
+                None => {
+                    fields.push(field);
+                    return Some(fields);
+                }
+                Some(_) => return None,
+            }
+        } else {
+            loop {
+                match chars.next() {
+                    Some(',') => break,
+                    Some('"') => return None,
+                    Some(c) => field.push(c),
+                    None => {
+                        fields.push(field);
+                        return Some(fields);
+                    }
+                }
+            }
+            fields.push(field);
+        }
+    }
+}
+
+fn invalid(line: usize, message: &str) -> Error {
+    Error::from(ErrorKind::InvalidFormat(format!(
+        "line {}: {}",
+        line, message
+    )))
+}
@@ -0,0 +1,185 @@
+//! Offline checks and repair of a `KvStore` directory, for stores which fail to open
+use crate::common::Command;
+use crate::error::Result;
+use crate::kvs_store::{db_path, KvStore};
+use crate::lock::DirLock;
+use crate::manifest::Manifest;
+use serde_json::Deserializer;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// A record of a db file and the bytes it takes
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub start: u64,
+    pub end: u64,
+    pub command: Command,
+}
+
+/// Where records of a db file stop being readable
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Corruption {
+    pub offset: u64,
+    pub message: String,
+}
+
+/// What a db file holds
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub no: u64,
+    /// size of file
+    pub len: u64,
+    pub records: usize,
+    /// bytes of sets holding the latest value of their key
+    pub live_bytes: u64,
+    /// bytes of readable records compaction would drop
+    pub stale_bytes: u64,
+    pub corrupt: Option<Corruption>,
+}
+
+/// What `repair` did to a db file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Repair {
+    /// records from `offset` on were cut off
+    Truncated { no: u64, offset: u64 },
+    /// no record could be read, file was set aside
+    Dropped { no: u64 },
+}
+
+/// numbers of db files of store at `dir` as listed in its manifest, oldest first.
+/// A store without manifest holds every db file in `dir`.
+pub fn segments(dir: &Path) -> Result<Vec<u64>> {
+    match Manifest::load(dir)? {
+        Some(manifest) => Ok(manifest.segments),
+        None => KvStore::db_list(&dir.to_path_buf()),
+    }
+}
+
+/// records of db file `no` up to the first one which can not be read
+pub fn read_segment(dir: &Path, no: u64) -> Result<(Vec<Record>, Option<Corruption>)> {
+    let path = db_path(&dir.to_path_buf(), no);
+    let reader = BufReader::new(File::open(&path)?);
+    let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
+    let mut records = Vec::new();
+    let mut start = 0;
+    while let Some(command) = stream.next() {
+        let command = match command {
+            Ok(Command::Get { .. }) => Err("get is not a record".to_string()),
+            command => command.map_err(|e| e.to_string()),
+        };
+        match command {
+            Ok(command) => {
+                let end = stream.byte_offset() as u64;
+                records.push(Record {
+                    start,
+                    end,
+                    command,
+                });
+                start = end;
+            }
+            Err(message) => {
+                let corruption = Corruption {
+                    offset: start,
+                    message,
+                };
+                return Ok((records, Some(corruption)));
+            }
+        }
+    }
+    Ok((records, None))
+}
+
+/// every db file of store at `dir`, with bytes live and stale as of the last one
+pub fn inspect(dir: &Path) -> Result<Vec<Segment>> {
+    let mut segments = Vec::new();
+    // latest set of each key, as segment and size
+    let mut live: HashMap<String, (usize, u64)> = HashMap::new();
+    for no in self::segments(dir)? {
+        let len = fs::metadata(db_path(&dir.to_path_buf(), no))?.len();
+        let (records, corrupt) = read_segment(dir, no)?;
+        let at = segments.len();
+        for record in &records {
+            match &record.command {
+                Command::Set { key, .. } => {
+                    live.insert(key.to_owned(), (at, record.end - record.start));
+                }
+                Command::Remove { key, .. } => {
+                    live.remove(key);
+                }
+                _ => {}
+            }
+        }
+        segments.push(Segment {
+            no,
+            len,
+            records: records.len(),
+            live_bytes: 0,
+            // every readable byte, until live ones are known
+            stale_bytes: records.iter().map(|record| record.end - record.start).sum(),
+            corrupt,
+        });
+    }
+    for (at, bytes) in live.values() {
+        segments[*at].live_bytes += bytes;
+        segments[*at].stale_bytes -= bytes;
+    }
+    Ok(segments)
+}
+
+/// db files of store at `dir` which can not be read to the end
+pub fn verify(dir: &Path) -> Result<Vec<Segment>> {
+    Ok(inspect(dir)?
+        .into_iter()
+        .filter(|segment| segment.corrupt.is_some())
+        .collect())
+}
+
+/// Cut corrupt tails off db files of store at `dir`, and set aside files without
+/// a readable record. Bytes taken out are kept in `N.corrupt` next to them,
+/// and manifest forgets the writes they held.
+pub fn repair(dir: &Path) -> Result<Vec<Repair>> {
+    let _lock = DirLock::acquire(dir)?;
+    let mut repairs = Vec::new();
+    let mut manifest = Manifest::load(dir)?;
+    for segment in verify(dir)? {
+        let no = segment.no;
+        let offset = segment
+            .corrupt
+            .map_or(segment.len, |corrupt| corrupt.offset);
+        let path = db_path(&dir.to_path_buf(), no);
+        if offset == 0 {
+            if let Some(manifest) = &mut manifest {
+                manifest.segments.retain(|&listed| listed != no);
+                manifest.save(dir)?;
+            }
+            fs::rename(&path, corrupt_path(dir, no))?;
+            repairs.push(Repair::Dropped { no });
+            continue;
+        }
+
+        let mut file = OpenOptions::new().read(true).write(true).open(&path)?;
+        let mut tail = Vec::new();
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_to_end(&mut tail)?;
+        let mut kept = File::create(corrupt_path(dir, no))?;
+        kept.write_all(&tail)?;
+        kept.sync_all()?;
+        file.set_len(offset)?;
+        file.sync_all()?;
+        repairs.push(Repair::Truncated { no, offset });
+    }
+    if let Some(manifest) = &mut manifest {
+        if !repairs.is_empty() {
+            // store counts again on open
+            manifest.seq = 0;
+            manifest.save(dir)?;
+        }
+    }
+    Ok(repairs)
+}
+
+fn corrupt_path(dir: &Path, no: u64) -> PathBuf {
+    dir.join(format!("{}.corrupt", no))
+}
@@ -1,13 +1,21 @@
+use crate::backup;
 use crate::common::{Command, KvsEngine, OffSet};
 use crate::error::{Error, ErrorKind, Result};
+use crate::lock::DirLock;
+use crate::manifest::{sync_dir, Manifest};
 use crate::reader::PosReader;
 use crate::writer::PosWriter;
 use serde_json::Deserializer;
 use std::cell::RefCell;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashSet, VecDeque};
 use std::ffi::OsStr;
+use std::fmt;
 use std::fs::{self, DirEntry, File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
 use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex, MutexGuard, RwLock};
 use std::{collections::HashMap, path::Path};
@@ -28,21 +36,95 @@ pub struct KvStore {
     path: Arc<PathBuf>,
     writer: Arc<Mutex<PosWriter<File>>>,
     readers: RefCell<HashMap<u64, PosReader<File>>>,
-    index: Arc<RwLock<HashMap<String, OffSet>>>,
+    index: Arc<RwLock<BTreeMap<String, OffSet>>>,
     // current number of database file
     current_no: Arc<AtomicU64>,
     // how many bytes not compacted
     wild: Arc<AtomicU64>,
+    // sequence number of last write
+    seq: Arc<AtomicU64>,
+    // writes after this one are all still in db files
+    floor: Arc<AtomicU64>,
+    // latest writes, so followers close behind need not read db files
+    recent: Arc<Mutex<VecDeque<Command>>>,
+    // held while db files are replaced by compaction or copied by backup
+    files: Arc<Mutex<()>>,
+    // keeps other processes out of directory until last clone is dropped
+    _lock: Arc<DirLock>,
+    // db files committed, saved after every change to them
+    manifest: Arc<Mutex<Manifest>>,
+    compact_threshold: u64,
+    durability: Durability,
+}
+
+/// When writes reach the disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    /// written to the OS before returning, fsynced on `flush`.
+    /// A crash of the machine may lose the latest writes.
+    Flush,
+    /// fsynced before returning
+    Sync,
+}
+
+impl FromStr for Durability {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "flush" => Ok(Durability::Flush),
+            "sync" => Ok(Durability::Sync),
+            _ => Err(Error::invalid_command(
+                "durability should be either flush or sync".to_string(),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Durability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Durability::Flush => write!(f, "flush"),
+            Durability::Sync => write!(f, "sync"),
+        }
+    }
 }
 
 impl KvStore {
-    const COMPACT_THRESHOLD: u64 = 8 * 1024 * 1024;
+    /// stale bytes in db files before they are compacted, unless changed
+    pub const COMPACT_THRESHOLD: u64 = 8 * 1024 * 1024;
+    const RECENT_WRITES: usize = 4096;
+    const ENGINE: &'static str = "kvs";
     pub fn open(path: &Path) -> Result<Self> {
         let path = path.join("");
         // create dir
         fs::create_dir_all(&path)?;
-        // list of all db file numbers
-        let db_list = KvStore::db_list(&path)?;
+        // before reading db files another process may be writing
+        let lock = Arc::new(DirLock::acquire(&path)?);
+        let on_disk = KvStore::db_list(&path)?;
+        let mut manifest = match Manifest::load(&path)? {
+            Some(manifest) if manifest.engine != KvStore::ENGINE => {
+                return Err(Error::from(ErrorKind::Storage(format!(
+                    "{} holds a {} store",
+                    path.display(),
+                    manifest.engine
+                ))))
+            }
+            Some(manifest) => manifest,
+            // made before manifests, every db file belongs to it
+            None => Manifest::new(KvStore::ENGINE, on_disk.clone()),
+        };
+        // left by a compaction or an open cut short, never committed
+        for no in on_disk.iter().filter(|no| !manifest.segments.contains(no)) {
+            fs::remove_file(db_path(&path, *no))?;
+        }
+        // compaction output never committed
+        for entry in fs::read_dir(&path)? {
+            let entry = entry?.path();
+            if entry.to_str().is_some_and(|name| name.ends_with(".db.tmp")) {
+                fs::remove_file(entry)?;
+            }
+        }
+        let db_list = manifest.segments.clone();
 
         // get current db file, always create new file when create a new db
         let no = db_list.last().unwrap_or(&0) + 1;
@@ -51,7 +133,7 @@ impl KvStore {
         let writer = Arc::new(Mutex::new(new_db_writer(&path, no)?));
         let readers = RefCell::new(HashMap::<u64, PosReader<File>>::new());
         // store all key and it's pointer in memory
-        let index: Arc<RwLock<HashMap<String, OffSet>>> = Arc::new(RwLock::new(HashMap::new()));
+        let index: Arc<RwLock<BTreeMap<String, OffSet>>> = Arc::new(RwLock::new(BTreeMap::new()));
 
         let path = Arc::new(path);
 
@@ -62,8 +144,17 @@ impl KvStore {
             index,
             current_no,
             wild: Arc::new(AtomicU64::new(0)),
+            seq: Arc::new(AtomicU64::new(0)),
+            floor: Arc::new(AtomicU64::new(0)),
+            recent: Arc::new(Mutex::new(VecDeque::new())),
+            files: Arc::new(Mutex::new(())),
+            _lock: lock,
+            manifest: Arc::new(Mutex::new(manifest.clone())),
+            compact_threshold: KvStore::COMPACT_THRESHOLD,
+            durability: Durability::Flush,
         };
         // insert current new db reader to readers
+        let mut unnumbered = false;
         {
             let current_reader = store.new_db_reader(no)?;
             let mut readers = store.readers.borrow_mut();
@@ -71,17 +162,52 @@ impl KvStore {
 
             // read data into memory from db files
             for &db in &db_list {
-                let file = File::open(db_path(&store.path, db))?;
+                let file = File::open(db_path(&store.path, db)).map_err(|e| {
+                    Error::from(ErrorKind::Storage(format!(
+                        "{}.db listed in manifest can not be opened: {}",
+                        db, e
+                    )))
+                })?;
                 let mut reader = PosReader::new(file)?;
-                store.load_from_db(db, &mut reader)?;
+                store.load_from_db(db, &mut reader, &mut unnumbered)?;
                 readers.insert(db, reader);
             }
         }
+        // writes from before numbering can only reach followers in a snapshot
+        if unnumbered {
+            let seq = store.seq.load(Ordering::SeqCst) + 1;
+            let mut writer = store.writer.lock().unwrap();
+            write_command(&mut writer, &Command::Mark { seq })?;
+            writer.flush()?;
+            store.seq.store(seq, Ordering::SeqCst);
+            store.floor.store(seq, Ordering::SeqCst);
+        }
+
+        let seq = store.seq.load(Ordering::SeqCst);
+        if seq < manifest.seq {
+            return Err(Error::from(ErrorKind::Storage(format!(
+                "{} lost writes {} to {}, see kvs-admin verify",
+                store.path.display(),
+                seq + 1,
+                manifest.seq
+            ))));
+        }
+        // new file is committed once every other one was read
+        manifest.segments.push(no);
+        manifest.seq = seq;
+        manifest.save(&store.path)?;
+        *store.manifest.lock().unwrap() = manifest;
 
         Ok(store)
     }
 
-    fn load_from_db(&self, no: u64, reader: &mut PosReader<File>) -> Result<()> {
+    // `unnumbered` tells whether writes without sequence number were read since last mark
+    fn load_from_db(
+        &self,
+        no: u64,
+        reader: &mut PosReader<File>,
+        unnumbered: &mut bool,
+    ) -> Result<()> {
         // move to start of file
         let mut pos = reader.seek(SeekFrom::Start(0))?;
         let reader = reader.reader();
@@ -90,7 +216,14 @@ impl KvStore {
         // parse command from file
         while let Some(cmd) = stream.next() {
             let new_pos = stream.byte_offset() as u64;
-            match cmd? {
+            let cmd = cmd.map_err(|e| corrupt(no, pos, &e))?;
+            match cmd.seq() {
+                0 => *unnumbered = true,
+                seq => {
+                    self.seq.fetch_max(seq, Ordering::SeqCst);
+                }
+            }
+            match cmd {
                 Command::Set { key, .. } => {
                     if let Ok(mut index) = self.index.write() {
                         if let Some(old_cmd) = index.insert(key, OffSet::new(no, pos, new_pos)) {
@@ -99,7 +232,7 @@ impl KvStore {
                         }
                     }
                 }
-                Command::Remove { key } => {
+                Command::Remove { key, .. } => {
                     if let Ok(mut index) = self.index.write() {
                         if let Some(old_cmd) = index.remove(&key) {
                             self.wild.fetch_add(old_cmd.len(), Ordering::SeqCst);
@@ -107,7 +240,12 @@ impl KvStore {
                         self.wild.fetch_add(new_pos - pos, Ordering::SeqCst);
                     }
                 }
-                _ => return Err(Error::invalid_command("invalid command parsed".to_string())),
+                Command::Mark { seq } => {
+                    self.floor.fetch_max(seq, Ordering::SeqCst);
+                    *unnumbered = false;
+                    self.wild.fetch_add(new_pos - pos, Ordering::SeqCst);
+                }
+                _ => return Err(corrupt(no, pos, &"invalid command parsed")),
             }
             pos = new_pos;
         }
@@ -115,63 +253,169 @@ impl KvStore {
         Ok(())
     }
 
+    /// compact once this many bytes of db files are stale
+    pub fn with_compact_threshold(mut self, bytes: u64) -> Self {
+        self.compact_threshold = bytes;
+        self
+    }
+
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
     pub fn compact(&self) -> Result<()> {
-        // number of file to compact
-        let compact_no = self.current_no.load(Ordering::SeqCst) + 1;
+        let _files = self.files.lock().unwrap();
+        self.compact_files()
+    }
 
-        let mut compact_writer = self.next_nth_db(1)?;
+    // compact when enough data is stale, unless a backup is copying files
+    fn compact_if_needed(&self) -> Result<()> {
+        if self.wild.load(Ordering::SeqCst) > self.compact_threshold {
+            // next write tries again
+            if let Ok(_files) = self.files.try_lock() {
+                self.compact_files()?;
+            }
+        }
+        Ok(())
+    }
 
-        // skip compact file
-        {
-            let next_pos_writer = self.next_nth_db(1)?;
+    // Compacted records go to a temp file, which is synced, renamed to its db file
+    // and then listed in manifest. A crash before that leaves the old files in use.
+    // Writes go on meanwhile, into the file after the compacted one.
+    fn compact_files(&self) -> Result<()> {
+        let (compact_no, seq, stale, live) = {
             let mut writer = self.writer.lock().expect("unable get lock");
-            *writer = next_pos_writer;
-        }
+            // skip compact file, it is not created until committed
+            let compact_no = self.roll(&mut writer, 1)? - 1;
+            // records of writes up to here are dropped or copied into compact file
+            let seq = self.seq.load(Ordering::SeqCst);
+            self.floor.store(seq, Ordering::SeqCst);
+            self.save_manifest(seq, |segments| segments.push(compact_no + 1))?;
+            // writes index their records before unlocking writer, so every one is here
+            let live: Vec<(String, OffSet)> = self
+                .index
+                .read()
+                .expect("unable get lock")
+                .iter()
+                .filter(|(_, offset)| offset.no() < compact_no)
+                .map(|(key, offset)| (key.to_owned(), offset.clone()))
+                .collect();
+            (compact_no, seq, self.wild.load(Ordering::SeqCst), live)
+        };
+        crash_point("compact-rolled");
 
-        let mut new_pos = 0;
         let mut readers = self.readers.borrow_mut();
-
-        if let Ok(mut index) = self.index.write() {
-            for cmd in index.values_mut() {
-                let reader = readers
-                    .get_mut(&cmd.no())
-                    .unwrap_or_else(|| panic!("unable to read file {}", cmd.no()));
-
-                // to the start of given offset
-                reader.seek(SeekFrom::Start(cmd.start()))?;
-
-                // read only length of offset
-                let mut cmd_reader = reader.take(cmd.len());
-                // write to
-                let len = std::io::copy(&mut cmd_reader, &mut compact_writer)?;
-                // update offset in memory
-                *cmd = OffSet::new(compact_no, new_pos, len + new_pos);
-
-                new_pos += len;
+        let tmp = tmp_path(&self.path, compact_no);
+        let moved = match self.write_compacted(&tmp, compact_no, seq, &mut readers, &live) {
+            Ok(moved) => moved,
+            Err(e) => {
+                let _ = fs::remove_file(&tmp);
+                return Err(e);
             }
-        }
-        compact_writer.flush()?;
-
-        // remove trash file
-        let trash_db: Vec<_> = readers
-            .keys()
+        };
+        fs::rename(&tmp, db_path(&self.path, compact_no))?;
+        sync_dir(&self.path)?;
+        crash_point("compact-renamed");
+
+        let trash_db: Vec<u64> = self
+            .manifest
+            .lock()
+            .unwrap()
+            .segments
+            .iter()
             .filter(|&&no| no < compact_no)
             .cloned()
             .collect();
+        self.save_manifest(seq, |segments| {
+            segments.retain(|&no| no > compact_no);
+            segments.push(compact_no);
+        })?;
+        crash_point("compact-committed");
+
+        // keys written or removed while copying keep their newer record,
+        // their copy is stale like the record it replaced
+        {
+            let mut index = self.index.write().expect("unable get lock");
+            for ((key, old), new) in live.into_iter().zip(moved) {
+                if let Some(offset) = index.get_mut(&key) {
+                    if *offset == old {
+                        *offset = new;
+                    }
+                }
+            }
+        }
 
+        // remove trash file
         for trash in trash_db {
             readers.remove(&trash);
             fs::remove_file(db_path(&self.path, trash))?;
+            crash_point("compact-removing");
         }
 
-        self.wild.store(0, Ordering::SeqCst);
+        // stale bytes counted since the roll are in newer files or the compacted one
+        self.wild.fetch_sub(stale, Ordering::SeqCst);
+        Ok(())
+    }
+
+    // copy records at `live` into `tmp` and sync it, returning where each one went
+    fn write_compacted(
+        &self,
+        tmp: &Path,
+        compact_no: u64,
+        seq: u64,
+        readers: &mut HashMap<u64, PosReader<File>>,
+        live: &[(String, OffSet)],
+    ) -> Result<Vec<OffSet>> {
+        let mut compact_writer = PosWriter::new(File::create(tmp)?)?;
+        let mut moved = Vec::with_capacity(live.len());
+        let mut new_pos = 0;
+        for (_, cmd) in live {
+            let reader = match readers.entry(cmd.no()) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => entry.insert(self.new_db_reader(cmd.no())?),
+            };
+
+            // to the start of given offset
+            reader.seek(SeekFrom::Start(cmd.start()))?;
+
+            // read only length of offset
+            let mut cmd_reader = reader.take(cmd.len());
+            // write to
+            let len = std::io::copy(&mut cmd_reader, &mut compact_writer)?;
+            moved.push(OffSet::new(compact_no, new_pos, len + new_pos));
+
+            new_pos += len;
+        }
+        compact_writer.flush()?;
+        crash_point("compact-copied");
+        write_command(&mut compact_writer, &Command::Mark { seq })?;
+        compact_writer.sync()?;
+        crash_point("compact-synced");
+        Ok(moved)
+    }
+
+    // change db files listed in manifest and save it, writes up to `seq` must be on disk
+    fn save_manifest(&self, seq: u64, update: impl FnOnce(&mut Vec<u64>)) -> Result<()> {
+        let mut manifest = self.manifest.lock().unwrap();
+        let mut next = manifest.clone();
+        update(&mut next.segments);
+        next.segments.sort_unstable();
+        next.seq = next.seq.max(seq);
+        next.save(&self.path)?;
+        *manifest = next;
         Ok(())
     }
 
-    // create next nth db file
-    fn next_nth_db(&self, n: u64) -> Result<PosWriter<File>> {
-        self.current_no.fetch_add(n, Ordering::SeqCst);
-        self.new_db(self.current_no.load(Ordering::SeqCst))
+    // Move writes to a new db file, leaving `skip` numbers unused before it,
+    // and return its number. Writes read the number under the same lock.
+    fn roll(&self, writer: &mut MutexGuard<PosWriter<File>>, skip: u64) -> Result<u64> {
+        let no = self.current_no.load(Ordering::SeqCst) + skip + 1;
+        let next_pos_writer = self.new_db(no)?;
+        writer.sync()?;
+        **writer = next_pos_writer;
+        self.current_no.store(no, Ordering::SeqCst);
+        Ok(no)
     }
     // create a new db file and return writer to it
     fn new_db(&self, no: u64) -> Result<PosWriter<File>> {
@@ -192,7 +436,7 @@ impl KvStore {
     }
 
     // get list of db files in path
-    fn db_list(path: &PathBuf) -> Result<Vec<u64>> {
+    pub(crate) fn db_list(path: &PathBuf) -> Result<Vec<u64>> {
         //
         let take_entry =
             |res: std::result::Result<DirEntry, std::io::Error>| -> Result<_> { Ok(res?.path()) };
@@ -221,12 +465,66 @@ impl KvStore {
         Ok(list)
     }
     // append result to db file
-    fn append(&self, writer: &mut MutexGuard<PosWriter<File>>, cmd: &Command) -> Result<()> {
-        let vec = serde_json::to_vec(cmd)?;
-        writer.write_all(&vec)?;
-        writer.flush()?;
+    fn append(&self, writer: &mut MutexGuard<PosWriter<File>>, cmd: Command) -> Result<()> {
+        self.log(writer, cmd)?;
+        self.commit(writer)?;
         Ok(())
     }
+
+    // end of a write, it reaches the disk too if durability asks for it
+    fn commit(&self, writer: &mut PosWriter<File>) -> Result<()> {
+        match self.durability {
+            Durability::Flush => writer.flush(),
+            Durability::Sync => writer.sync(),
+        }
+    }
+
+    // sequence number for next write, only valid while writer is locked
+    fn next_seq(&self) -> u64 {
+        self.seq.load(Ordering::SeqCst) + 1
+    }
+
+    // write a numbered command, keeping it for followers
+    fn log(&self, writer: &mut PosWriter<File>, cmd: Command) -> Result<()> {
+        write_command(writer, &cmd)?;
+        self.seq.store(cmd.seq(), Ordering::SeqCst);
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() == KvStore::RECENT_WRITES {
+            recent.pop_front();
+        }
+        recent.push_back(cmd);
+        Ok(())
+    }
+
+    // read value of a set command at given offset
+    fn read_value(
+        &self,
+        readers: &mut HashMap<u64, PosReader<File>>,
+        offset: &OffSet,
+    ) -> Result<String> {
+        // initialize a new reader
+        if !readers.contains_key(&offset.no()) {
+            let no = offset.no();
+            let path = db_path(&self.path, no);
+            let file = File::open(path)?;
+            let reader = PosReader::new(file)?;
+            readers.insert(no, reader);
+        }
+
+        let reader = readers.get_mut(&offset.no()).expect("reader not found");
+        reader.seek(SeekFrom::Start(offset.start()))?;
+        let cmd_reader = reader.take(offset.len());
+
+        if let Command::Set { value, .. } = serde_json::from_reader(cmd_reader)? {
+            return Ok(value);
+        }
+
+        Err(Error::from(ErrorKind::Storage(format!(
+            "invalid command at db file:{}, position:{}",
+            offset.no(),
+            offset.start()
+        ))))
+    }
 }
 
 impl KvsEngine for KvStore {
@@ -238,31 +536,31 @@ impl KvsEngine for KvStore {
     /// ```
     /// ```
     fn set(&self, key: String, value: String) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
         let cmd = Command::Set {
             key: key.to_owned(),
             value,
+            seq: self.next_seq(),
         };
-
-        let mut writer = self.writer.lock().unwrap();
         let current_pos = writer.pos();
         // append command to db file
-        self.append(&mut writer, &cmd)?;
+        self.append(&mut writer, cmd)?;
         let new_pos = writer.pos();
-        // unlock writer
-        drop(writer);
-
-        let offset = OffSet::new(self.current_no.load(Ordering::SeqCst), current_pos, new_pos);
+        let no = self.current_no.load(Ordering::SeqCst);
+        let offset = OffSet::new(no, current_pos, new_pos);
 
-        // try to compact db files
+        // indexed before writer is unlocked, or compaction could drop the file first
         if let Ok(mut index) = self.index.write() {
             if let Some(old_cmd) = index.insert(key, offset) {
                 self.wild.fetch_add(old_cmd.len(), Ordering::SeqCst);
             }
         }
+        // unlock writer
+        drop(writer);
 
-        if self.wild.load(Ordering::SeqCst) > KvStore::COMPACT_THRESHOLD {
-            self.compact()?;
-        }
+        // try to compact db files
+
+        self.compact_if_needed()?;
         Ok(())
     }
     /// set the value of a given key
@@ -274,64 +572,314 @@ impl KvsEngine for KvStore {
         if let Ok(index) = self.index.write() {
             // check key in memory
             if let Some(offset) = index.get(&key) {
-                // initialize a new reader
-                if !readers.contains_key(&offset.no()) {
-                    let no = offset.no();
-                    let path = db_path(&self.path, no);
-                    let file = File::open(path)?;
-                    let reader = PosReader::new(file)?;
-                    readers.insert(no, reader);
-                }
+                return Ok(Some(self.read_value(&mut readers, offset)?));
+            }
+        }
 
-                if let Some(reader) = readers.get_mut(&offset.no()) {
-                    reader.seek(SeekFrom::Start(offset.start()))?;
-                    let cmd_reader = reader.take(offset.len());
+        Ok(None)
+    }
+    /// get values of many keys, taking index lock once
+    /// and reading in file order
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let mut readers = self.readers.borrow_mut();
+        let mut values = vec![None; keys.len()];
+
+        if let Ok(index) = self.index.read() {
+            let mut offsets: Vec<(usize, &OffSet)> = keys
+                .iter()
+                .enumerate()
+                .filter_map(|(i, key)| index.get(key).map(|offset| (i, offset)))
+                .collect();
+            // sort by file and position for locality
+            offsets.sort_unstable_by_key(|(_, offset)| (offset.no(), offset.start()));
+
+            for (i, offset) in offsets {
+                values[i] = Some(self.read_value(&mut readers, offset)?);
+            }
+        }
+
+        Ok(values)
+    }
+    /// walks index from `after`, which keeps keys in order
+    fn scan(&self, after: Option<String>, limit: usize) -> Result<Vec<(String, String)>> {
+        let mut readers = self.readers.borrow_mut();
+        let index = self.index.read().unwrap();
+        let start = match &after {
+            Some(after) => Bound::Excluded(after),
+            None => Bound::Unbounded,
+        };
+        index
+            .range::<String, _>((start, Bound::Unbounded))
+            .take(limit)
+            .map(|(key, offset)| Ok((key.to_owned(), self.read_value(&mut readers, offset)?)))
+            .collect()
+    }
+    /// set many pairs with one flush
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        let mut offsets = Vec::with_capacity(pairs.len());
+        {
+            let mut writer = self.writer.lock().unwrap();
+            let no = self.current_no.load(Ordering::SeqCst);
+            for (key, value) in pairs {
+                let current_pos = writer.pos();
+                let cmd = Command::Set {
+                    key: key.to_owned(),
+                    value,
+                    seq: self.next_seq(),
+                };
+                self.log(&mut writer, cmd)?;
+                offsets.push((key, OffSet::new(no, current_pos, writer.pos())));
+            }
+            self.commit(&mut writer)?;
 
-                    if let Command::Set { value, .. } = serde_json::from_reader(cmd_reader)? {
-                        return Ok(Some(value));
+            if let Ok(mut index) = self.index.write() {
+                for (key, offset) in offsets {
+                    if let Some(old_cmd) = index.insert(key, offset) {
+                        self.wild.fetch_add(old_cmd.len(), Ordering::SeqCst);
                     }
+                }
+            }
+        }
 
-                    return Err(Error::from(ErrorKind::InvalidCommand(format!(
-                        "invalid command at db file:{}, position:{}",
-                        offset.no(),
-                        offset.start()
-                    ))));
+        self.compact_if_needed()?;
+        Ok(())
+    }
+    /// remove many keys with one flush, tells which keys existed
+    fn remove_many(&self, keys: Vec<String>) -> Result<Vec<bool>> {
+        let mut removed = Vec::with_capacity(keys.len());
+        {
+            let mut writer = self.writer.lock().unwrap();
+            let mut index = self.index.write().unwrap();
+            for key in keys {
+                if !index.contains_key(&key) {
+                    removed.push(false);
+                    continue;
                 }
+                let seq = self.next_seq();
+                self.log(
+                    &mut writer,
+                    Command::Remove {
+                        key: key.to_owned(),
+                        seq,
+                    },
+                )?;
+                if let Some(offset) = index.remove(&key) {
+                    self.wild.fetch_add(offset.len(), Ordering::SeqCst);
+                }
+                removed.push(true);
             }
+            self.commit(&mut writer)?;
         }
 
-        Ok(None)
+        self.compact_if_needed()?;
+        Ok(removed)
+    }
+    /// flush and fsync current db file, recording last write in manifest
+    fn flush(&self) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        writer.sync()?;
+        self.save_manifest(self.seq.load(Ordering::SeqCst), |_| {})
     }
-    /// remove a given key in store
-    /// ```
-    /// ```
-    fn remove(&self, key: String) -> Result<String> {
-        match self.get(key.to_owned())? {
-            Some(value) => {
-                let cmd = Command::Remove {
-                    key: key.to_owned(),
-                };
 
-                {
-                    let mut writer = self.writer.lock().unwrap();
-                    self.append(&mut writer, &cmd)?;
+    fn last_seq(&self) -> u64 {
+        self.seq.load(Ordering::SeqCst)
+    }
 
-                    let mut index = self.index.write().unwrap();
-                    let offset = index.remove(&key).expect("key not found");
-                    self.wild.fetch_add(offset.len(), Ordering::SeqCst);
+    /// served from recent writes when possible, otherwise read from db files
+    fn changes_since(&self, seq: u64, limit: usize) -> Result<Option<Vec<Command>>> {
+        // db files are neither compacted nor written while they are read
+        let _files = self.files.lock().unwrap();
+        let _writer = self.writer.lock().unwrap();
+        let last = self.seq.load(Ordering::SeqCst);
+        // a store ahead of this one has writes it never made
+        if seq > last || seq < self.floor.load(Ordering::SeqCst) {
+            return Ok(None);
+        }
+        if seq == last {
+            return Ok(Some(Vec::new()));
+        }
+
+        let recent = self.recent.lock().unwrap();
+        if recent.front().is_some_and(|first| first.seq() <= seq + 1) {
+            let changes = recent.iter().filter(|cmd| cmd.seq() > seq);
+            return Ok(Some(changes.take(limit).cloned().collect()));
+        }
+        drop(recent);
+
+        let mut changes = Vec::new();
+        let segments = self.manifest.lock().unwrap().segments.clone();
+        for no in segments {
+            let file = match File::open(db_path(&self.path, no)) {
+                Ok(file) => BufReader::new(file),
+                // follower catches up from a snapshot instead
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(Error::from(e)),
+            };
+            for cmd in Deserializer::from_reader(file).into_iter::<Command>() {
+                let cmd = cmd?;
+                let write = matches!(cmd, Command::Set { .. } | Command::Remove { .. });
+                if write && cmd.seq() > seq {
+                    changes.push(cmd);
+                    if changes.len() == limit {
+                        return Ok(Some(changes));
+                    }
                 }
+            }
+        }
+        Ok(Some(changes))
+    }
+
+    fn snapshot(&self) -> Result<(u64, Vec<(String, String)>)> {
+        // no write slips in between reading sequence number and values
+        let _writer = self.writer.lock().unwrap();
+        let seq = self.seq.load(Ordering::SeqCst);
+        let mut readers = self.readers.borrow_mut();
+        let index = self.index.read().unwrap();
+        let pairs = index
+            .iter()
+            .map(|(key, offset)| Ok((key.to_owned(), self.read_value(&mut readers, offset)?)))
+            .collect::<Result<_>>()?;
+        Ok((seq, pairs))
+    }
 
-                if self.wild.load(Ordering::SeqCst) > KvStore::COMPACT_THRESHOLD {
-                    self.compact()?;
+    /// writes keep sequence numbers they were given, ones applied already are skipped
+    fn apply(&self, commands: Vec<Command>) -> Result<()> {
+        {
+            let mut writer = self.writer.lock().unwrap();
+            let mut index = self.index.write().unwrap();
+            let no = self.current_no.load(Ordering::SeqCst);
+            for cmd in commands {
+                if cmd.seq() <= self.seq.load(Ordering::SeqCst) {
+                    continue;
                 }
+                let old_cmd = match &cmd {
+                    Command::Set { key, .. } => {
+                        let key = key.to_owned();
+                        let current_pos = writer.pos();
+                        self.log(&mut writer, cmd)?;
+                        index.insert(key, OffSet::new(no, current_pos, writer.pos()))
+                    }
+                    Command::Remove { key, .. } => {
+                        let key = key.to_owned();
+                        self.log(&mut writer, cmd)?;
+                        index.remove(&key)
+                    }
+                    _ => {
+                        return Err(Error::invalid_command(format!(
+                            "only writes can be applied, got {:?}",
+                            cmd
+                        )))
+                    }
+                };
+                if let Some(old_cmd) = old_cmd {
+                    self.wild.fetch_add(old_cmd.len(), Ordering::SeqCst);
+                }
+            }
+            self.commit(&mut writer)?;
+        }
+
+        self.compact_if_needed()?;
+        Ok(())
+    }
+
+    /// db files are frozen by moving writes to a new file, then copied.
+    /// Compaction waits until copying is done.
+    fn backup(&self, dest: &Path) -> Result<backup::Manifest> {
+        let _files = self.files.lock().unwrap();
+        let (no, seq) = {
+            let mut writer = self.writer.lock().unwrap();
+            let no = self.roll(&mut writer, 0)?;
+            let seq = self.seq.load(Ordering::SeqCst);
+            self.save_manifest(seq, |segments| segments.push(no))?;
+            (no, seq)
+        };
+        let frozen: Vec<u64> = self
+            .manifest
+            .lock()
+            .unwrap()
+            .segments
+            .iter()
+            .copied()
+            .filter(|&file| file < no)
+            .collect();
+        backup::write(&self.path, dest, seq, &frozen)
+    }
 
-                Ok(value)
+    fn restore(&self, seq: u64, pairs: Vec<(String, String)>) -> Result<()> {
+        {
+            let mut writer = self.writer.lock().unwrap();
+            let mut index = self.index.write().unwrap();
+            let no = self.current_no.load(Ordering::SeqCst);
+
+            let keep: HashSet<&String> = pairs.iter().map(|(key, _)| key).collect();
+            let gone: Vec<String> = index
+                .keys()
+                .filter(|key| !keep.contains(key))
+                .cloned()
+                .collect();
+            // records of a snapshot are not numbered, the mark after them is
+            for key in gone {
+                if let Some(old_cmd) = index.remove(&key) {
+                    self.wild.fetch_add(old_cmd.len(), Ordering::SeqCst);
+                }
+                write_command(&mut writer, &Command::Remove { key, seq: 0 })?;
             }
-            None => Err(Error::from(ErrorKind::KeyNotFound(format!(
-                "key {} not found",
-                key
-            )))),
+            for (key, value) in pairs {
+                let current_pos = writer.pos();
+                let cmd = Command::Set {
+                    key: key.to_owned(),
+                    value,
+                    seq: 0,
+                };
+                write_command(&mut writer, &cmd)?;
+                if let Some(old_cmd) = index.insert(key, OffSet::new(no, current_pos, writer.pos()))
+                {
+                    self.wild.fetch_add(old_cmd.len(), Ordering::SeqCst);
+                }
+            }
+            write_command(&mut writer, &Command::Mark { seq })?;
+            self.commit(&mut writer)?;
+
+            self.seq.store(seq, Ordering::SeqCst);
+            self.floor.store(seq, Ordering::SeqCst);
+            self.recent.lock().unwrap().clear();
         }
+
+        self.compact_if_needed()?;
+        Ok(())
+    }
+    /// remove a given key in store
+    /// ```
+    /// ```
+    fn remove(&self, key: String) -> Result<String> {
+        let value = {
+            // key is checked and removed under the same locks, so only one remove logs it
+            let mut writer = self.writer.lock().unwrap();
+            let mut index = self.index.write().unwrap();
+            let value = match index.get(&key) {
+                Some(offset) => self.read_value(&mut self.readers.borrow_mut(), offset)?,
+                None => {
+                    return Err(Error::from(ErrorKind::KeyNotFound(format!(
+                        "key {} not found",
+                        key
+                    ))))
+                }
+            };
+            let cmd = Command::Remove {
+                key: key.to_owned(),
+                seq: self.next_seq(),
+            };
+            self.append(&mut writer, cmd)?;
+
+            if let Some(offset) = index.remove(&key) {
+                self.wild.fetch_add(offset.len(), Ordering::SeqCst);
+            }
+            value
+        };
+
+        self.compact_if_needed()?;
+
+        Ok(value)
     }
 }
 
@@ -344,10 +892,23 @@ impl Clone for KvStore {
             index: Arc::clone(&self.index),
             current_no: Arc::clone(&self.current_no),
             wild: Arc::clone(&self.wild),
+            seq: Arc::clone(&self.seq),
+            floor: Arc::clone(&self.floor),
+            recent: Arc::clone(&self.recent),
+            files: Arc::clone(&self.files),
+            _lock: Arc::clone(&self._lock),
+            manifest: Arc::clone(&self.manifest),
+            compact_threshold: self.compact_threshold,
+            durability: self.durability,
         }
     }
 }
 
+fn write_command(writer: &mut PosWriter<File>, cmd: &Command) -> Result<()> {
+    writer.write_all(&serde_json::to_vec(cmd)?)?;
+    Ok(())
+}
+
 fn new_db_writer(path: &PathBuf, no: u64) -> Result<PosWriter<File>> {
     let path = db_path(path, no);
     let writer = OpenOptions::new()
@@ -365,7 +926,30 @@ fn new_db_reader(path: &PathBuf, no: u64) -> Result<PosReader<File>> {
     let reader = PosReader::new(file)?;
     Ok(reader)
 }
+// error opening a store with unreadable record at `pos` of db file `no`
+fn corrupt(no: u64, pos: u64, e: &dyn std::fmt::Display) -> Error {
+    Error::from(ErrorKind::Storage(format!(
+        "{}.db is corrupt at byte {}: {}, see kvs-admin verify and repair",
+        no, pos, e
+    )))
+}
+// Ends the process at `step` of compaction when `KVS_CRASH_AT` names it,
+// so tests can check what a crash there leaves behind.
+// Only built with the crash-test feature.
+#[cfg(any(test, feature = "crash-test"))]
+fn crash_point(step: &str) {
+    if std::env::var_os("KVS_CRASH_AT").is_some_and(|at| at == step) {
+        std::process::abort();
+    }
+}
+
+#[cfg(not(any(test, feature = "crash-test")))]
+fn crash_point(_step: &str) {}
+// get path to compaction output of db file `no` before it is committed
+fn tmp_path(path: &Path, no: u64) -> PathBuf {
+    path.join(format!("{}.db.tmp", no))
+}
 // get path to given db file
-fn db_path(path: &PathBuf, no: u64) -> PathBuf {
+pub(crate) fn db_path(path: &PathBuf, no: u64) -> PathBuf {
     path.join(format!("{}.db", no))
 }
@@ -1,10 +1,21 @@
+pub mod auth;
+pub mod backup;
 pub mod client;
 pub mod common;
 pub mod error;
+pub mod export;
+pub mod inspect;
 pub mod kvs_store;
+mod lock;
+pub mod manifest;
 mod net;
 mod protocol;
+pub mod raft;
 mod reader;
+pub mod replication;
 pub mod server;
+pub mod shard;
+mod stream;
 pub mod thread_pool;
+pub mod tls;
 mod writer;
@@ -0,0 +1,55 @@
+use crate::error::{Error, ErrorKind, Result};
+use fs2::FileExt;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::process;
+
+/// File in a store directory locked by the process using it
+pub(crate) const LOCK_FILE: &str = "LOCK";
+
+/// Exclusive advisory lock on a directory, released on drop.
+/// The file holds the pid of the process holding it.
+#[derive(Debug)]
+pub(crate) struct DirLock {
+    file: File,
+}
+
+impl DirLock {
+    /// lock `dir`, failing at once if another process holds it
+    pub(crate) fn acquire(dir: &Path) -> Result<DirLock> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            // pid of holder is read when lock is taken
+            .truncate(false)
+            .open(dir.join(LOCK_FILE))?;
+        if file.try_lock_exclusive().is_err() {
+            let mut pid = String::new();
+            file.read_to_string(&mut pid)?;
+            let holder = match pid.trim() {
+                // holder did not write its pid yet
+                "" => "another process".to_string(),
+                pid => format!("process {}", pid),
+            };
+            return Err(Error::from(ErrorKind::Locked(format!(
+                "{} is in use by {}",
+                dir.display(),
+                holder
+            ))));
+        }
+        // pid of a holder gone since is left in file
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        write!(file, "{}", process::id())?;
+        file.sync_all()?;
+        Ok(DirLock { file })
+    }
+}
+
+impl Drop for DirLock {
+    fn drop(&mut self) {
+        let _ = self.file.unlock();
+    }
+}
@@ -0,0 +1,87 @@
+//! Metadata of a store directory, replaced as a whole on every change
+use crate::error::{Error, ErrorKind, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// File in a store directory holding its manifest
+pub const MANIFEST_FILE: &str = "MANIFEST";
+/// Format of manifest and db files written by this version
+pub const FORMAT_VERSION: u32 = 1;
+
+/// What a store directory holds.
+/// A db file not listed was never committed and is not read.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    pub version: u32,
+    pub engine: String,
+    /// seconds since unix epoch when the store was made
+    pub created: u64,
+    /// numbers of db files holding the store, oldest first
+    pub segments: Vec<u64>,
+    /// sequence number of the last write when saved, later ones may follow in db files
+    pub seq: u64,
+}
+
+impl Manifest {
+    pub fn new(engine: &str, segments: Vec<u64>) -> Manifest {
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        Manifest {
+            version: FORMAT_VERSION,
+            engine: engine.to_owned(),
+            created,
+            segments,
+            seq: 0,
+        }
+    }
+
+    /// manifest of store at `dir`, `None` for a store made before manifests or not made yet
+    pub fn load(dir: &Path) -> Result<Option<Manifest>> {
+        let manifest: Manifest = match fs::read(dir.join(MANIFEST_FILE)) {
+            Ok(buf) => serde_json::from_slice(&buf).map_err(|e| {
+                Error::from(ErrorKind::Storage(format!(
+                    "{} of {} is corrupt: {}",
+                    MANIFEST_FILE,
+                    dir.display(),
+                    e
+                )))
+            })?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(Error::from(e)),
+        };
+        if manifest.version > FORMAT_VERSION {
+            return Err(Error::from(ErrorKind::UnsupportedVersion(format!(
+                "{} was made by a newer version, format {} is not supported",
+                dir.display(),
+                manifest.version
+            ))));
+        }
+        Ok(Some(manifest))
+    }
+
+    /// replace manifest of store at `dir`, readers see either the old or the new one
+    pub(crate) fn save(&self, dir: &Path) -> Result<()> {
+        let tmp = dir.join("MANIFEST.tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&serde_json::to_vec(self)?)?;
+        file.sync_all()?;
+        fs::rename(&tmp, dir.join(MANIFEST_FILE))?;
+        sync_dir(dir)
+    }
+}
+
+// make a rename in `dir` survive a crash
+#[cfg(unix)]
+pub(crate) fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn sync_dir(_dir: &Path) -> Result<()> {
+    Ok(())
+}
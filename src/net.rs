@@ -1,30 +1,212 @@
+use crate::backup::Manifest;
+use crate::common::Command;
+use crate::error::{Error, ErrorKind};
+use crate::raft::RaftMessage;
+use crate::server::ServerStats;
 use serde::{Deserialize, Serialize};
-use std::fs::OpenOptions;
+
+/// Latest protocol version spoken by this crate
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Oldest protocol version the server still accepts
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+/// Optional features the server can agree on during handshake
+pub const FEATURES: &[&str] = &[
+    "typed-errors",
+    "multi",
+    "auth",
+    "stats",
+    "replication",
+    "raft",
+    "scan",
+    "backup",
+];
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Request {
-    Get { key: String },
-    Set { key: String, value: String },
-    Remove { key: String },
+    Handshake {
+        version: u32,
+        features: Vec<String>,
+    },
+    Auth {
+        user: String,
+        password: String,
+    },
+    // braces make it a json object, stream deserializer reads nothing else
+    Stats {},
+    Get {
+        key: String,
+    },
+    Set {
+        key: String,
+        value: String,
+    },
+    Remove {
+        key: String,
+    },
+    GetMany {
+        keys: Vec<String>,
+    },
+    SetMany {
+        pairs: Vec<(String, String)>,
+    },
+    RemoveMany {
+        keys: Vec<String>,
+    },
+    /// up to `limit` pairs in key order, starting after key `after`
+    Scan {
+        after: Option<String>,
+        limit: usize,
+    },
+    /// copy store into directory `dest` on server, which must be empty
+    Backup {
+        dest: String,
+    },
+    /// writes made after `seq`, sent by followers
+    Replicate {
+        seq: u64,
+    },
+    /// sent between nodes of a raft cluster
+    Raft {
+        message: RaftMessage,
+    },
+    /// let node at `addr` join the raft cluster
+    AddMember {
+        addr: String,
+    },
+    RemoveMember {
+        addr: String,
+    },
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Response {
-    Get(Result<Option<String>, String>),
-    Set(Result<(), String>),
-    Remove(Result<(), String>),
+    Handshake(Result<Handshake, ServerError>),
+    Auth(Result<(), ServerError>),
+    Stats(Result<ServerStats, ServerError>),
+    // sent for requests the server does not understand
+    Error(ServerError),
+    Get(Result<Option<String>, ServerError>),
+    Set(Result<(), ServerError>),
+    Remove(Result<(), ServerError>),
+    GetMany(Result<Vec<Option<String>>, ServerError>),
+    SetMany(Result<(), ServerError>),
+    RemoveMany(Result<Vec<bool>, ServerError>),
+    Scan(Result<Vec<(String, String)>, ServerError>),
+    Backup(Result<Manifest, ServerError>),
+    Replicate(Result<Replication, ServerError>),
+    Raft(Result<RaftMessage, ServerError>),
+    // members of the raft cluster after the change
+    Members(Result<Vec<String>, ServerError>),
+}
+
+/// What a follower needs to catch up with its leader
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Replication {
+    /// next writes in order, none when follower is up to date
+    Changes(Vec<Command>),
+    /// writes the follower needs are gone, it has to start over from every pair stored
+    Snapshot {
+        seq: u64,
+        pairs: Vec<(String, String)>,
+    },
+}
+
+/// Version and features agreed on by client and server
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Handshake {
+    pub version: u32,
+    pub features: Vec<String>,
+}
+
+/// Errors sent over the wire, so that clients can tell failures apart
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ServerError {
+    KeyNotFound(String),
+    InvalidRequest(String),
+    Storage(String),
+    ReadOnly(String),
+    Overloaded(String),
+    UnsupportedVersion(String),
+    Unauthenticated(String),
+    PermissionDenied(String),
+    /// address of the leader, if node knows it
+    NotLeader(String, Option<String>),
+    Internal(String),
+}
+
+impl Request {
+    /// whether request only reads stored data
+    pub fn is_read(&self) -> bool {
+        matches!(
+            self,
+            Request::Get { .. } | Request::GetMany { .. } | Request::Scan { .. }
+        )
+    }
+
+    /// whether request changes stored data
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            Request::Set { .. }
+                | Request::Remove { .. }
+                | Request::SetMany { .. }
+                | Request::RemoveMany { .. }
+        )
+    }
 }
 
 impl Response {
-    pub fn set(result: Result<(), String>) -> Self {
+    pub fn set(result: Result<(), ServerError>) -> Self {
         Response::Set(result)
     }
 
-    pub fn get(result: Result<Option<String>, String>) -> Self {
+    pub fn get(result: Result<Option<String>, ServerError>) -> Self {
         Response::Get(result)
     }
 
-    pub fn remove(result: Result<(), String>) -> Self {
+    pub fn remove(result: Result<(), ServerError>) -> Self {
         Response::Remove(result)
     }
 }
+
+impl From<Error> for ServerError {
+    fn from(err: Error) -> Self {
+        let message = err.to_string();
+        match err.kind() {
+            ErrorKind::KeyNotFound(_) => ServerError::KeyNotFound(message),
+            ErrorKind::InvalidCommand(_)
+            | ErrorKind::InvalidFormat(_)
+            | ErrorKind::Incomplete(_)
+            | ErrorKind::Utf8ConversionError(_) => ServerError::InvalidRequest(message),
+            ErrorKind::IO(_)
+            | ErrorKind::SerializerError(_)
+            | ErrorKind::Storage(_)
+            | ErrorKind::Locked(_) => ServerError::Storage(message),
+            ErrorKind::ReadOnly(_) => ServerError::ReadOnly(message),
+            ErrorKind::Overloaded(_) => ServerError::Overloaded(message),
+            ErrorKind::UnsupportedVersion(_) => ServerError::UnsupportedVersion(message),
+            ErrorKind::Unauthenticated(_) => ServerError::Unauthenticated(message),
+            ErrorKind::PermissionDenied(_) => ServerError::PermissionDenied(message),
+            ErrorKind::NotLeader(_, leader) => ServerError::NotLeader(message, leader.clone()),
+            _ => ServerError::Internal(message),
+        }
+    }
+}
+
+impl From<ServerError> for Error {
+    fn from(err: ServerError) -> Self {
+        let kind = match err {
+            ServerError::KeyNotFound(msg) => ErrorKind::KeyNotFound(msg),
+            ServerError::InvalidRequest(msg) => ErrorKind::InvalidCommand(msg),
+            ServerError::Storage(msg) => ErrorKind::Storage(msg),
+            ServerError::ReadOnly(msg) => ErrorKind::ReadOnly(msg),
+            ServerError::Overloaded(msg) => ErrorKind::Overloaded(msg),
+            ServerError::UnsupportedVersion(msg) => ErrorKind::UnsupportedVersion(msg),
+            ServerError::Unauthenticated(msg) => ErrorKind::Unauthenticated(msg),
+            ServerError::PermissionDenied(msg) => ErrorKind::PermissionDenied(msg),
+            ServerError::NotLeader(msg, leader) => ErrorKind::NotLeader(msg, leader),
+            ServerError::Internal(msg) => ErrorKind::Error(msg),
+        };
+        Error::from(kind)
+    }
+}
@@ -0,0 +1,127 @@
+use crate::client::Client;
+use crate::error::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+
+// how many nodes to ask before giving up on a request
+const ATTEMPTS: usize = 20;
+// how long to wait for an election before asking again
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Talks to the leader of a raft cluster, following redirects from other nodes
+pub struct ClusterClient<'a> {
+    nodes: Vec<SocketAddr>,
+    // node to try next
+    next: usize,
+    client: Option<Client<'a>>,
+    auth: Option<(String, String)>,
+}
+
+impl<'a> ClusterClient<'a> {
+    /// client of cluster having some of `nodes`, it connects on first request
+    pub fn new(nodes: Vec<SocketAddr>) -> Self {
+        ClusterClient {
+            nodes,
+            next: 0,
+            client: None,
+            auth: None,
+        }
+    }
+
+    /// log in as `user` on every node connected to
+    pub fn with_auth(mut self, user: String, password: String) -> Self {
+        self.auth = Some((user, password));
+        self
+    }
+
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.call(|client| client.get(key.clone()))
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.call(|client| client.set(key.clone(), value.clone()))
+    }
+
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.call(|client| client.remove(key.clone()))
+    }
+
+    pub fn add_member(&mut self, addr: String) -> Result<Vec<String>> {
+        self.call(|client| client.add_member(addr.clone()))
+    }
+
+    pub fn remove_member(&mut self, addr: String) -> Result<Vec<String>> {
+        self.call(|client| client.remove_member(addr.clone()))
+    }
+
+    /// run `request` on the leader, retrying on other nodes while it can not be reached.
+    /// A write retried after losing its connection may be applied twice.
+    pub fn call<R, F>(&mut self, mut request: F) -> Result<R>
+    where
+        F: FnMut(&mut Client<'a>) -> Result<R>,
+    {
+        let mut last_error = None;
+        for _ in 0..ATTEMPTS {
+            let client = match self.connected() {
+                Ok(client) => client,
+                Err(e) => {
+                    thread::sleep(RETRY_INTERVAL);
+                    last_error = Some(e);
+                    continue;
+                }
+            };
+            match request(client) {
+                Err(e) => match e.kind() {
+                    ErrorKind::NotLeader(_, Some(leader)) => {
+                        self.redirect(leader);
+                        last_error = Some(e);
+                    }
+                    // election going on, or node is down
+                    ErrorKind::NotLeader(_, None) | ErrorKind::IO(_) => {
+                        self.client = None;
+                        thread::sleep(RETRY_INTERVAL);
+                        last_error = Some(e);
+                    }
+                    _ => return Err(e),
+                },
+                result => return result,
+            }
+        }
+        Err(last_error
+            .unwrap_or_else(|| Error::from(ErrorKind::Error("no node to send to".to_string()))))
+    }
+
+    fn connected(&mut self) -> Result<&mut Client<'a>> {
+        if self.client.is_none() {
+            if self.nodes.is_empty() {
+                return Err(Error::from(ErrorKind::Error(
+                    "cluster has no nodes".to_string(),
+                )));
+            }
+            let addr = self.nodes[self.next % self.nodes.len()];
+            // next failure moves on to another node
+            self.next += 1;
+            let mut client = Client::connect(addr)?;
+            if let Some((user, password)) = &self.auth {
+                client.auth(user.to_owned(), password.to_owned())?;
+            }
+            self.client = Some(client);
+        }
+        Ok(self.client.as_mut().unwrap())
+    }
+
+    // connect to `leader` next, learning about it if it is new
+    fn redirect(&mut self, leader: &str) {
+        self.client = None;
+        if let Ok(leader) = leader.parse() {
+            match self.nodes.iter().position(|node| *node == leader) {
+                Some(position) => self.next = position,
+                None => {
+                    self.nodes.push(leader);
+                    self.next = self.nodes.len() - 1;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,233 @@
+use super::message::{Entry, Payload};
+use crate::error::{Error, ErrorKind, Result};
+use crate::manifest::sync_dir;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+const STATE_FILE: &str = "state.json";
+const LOG_FILE: &str = "log.json";
+
+/// What a node must remember across restarts besides its entries
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct HardState {
+    pub term: u64,
+    pub voted_for: Option<String>,
+    // entries up to here were applied to the store and dropped
+    pub snapshot_index: u64,
+    pub snapshot_term: u64,
+    // members as of snapshot
+    pub members: Vec<String>,
+}
+
+/// Entries of a node kept in a directory, one json record per entry
+pub struct RaftLog {
+    dir: PathBuf,
+    hard: HardState,
+    entries: Vec<Entry>,
+    writer: BufWriter<File>,
+}
+
+impl RaftLog {
+    pub fn open(dir: &Path) -> Result<RaftLog> {
+        fs::create_dir_all(dir)?;
+        let hard = match fs::read(dir.join(STATE_FILE)) {
+            Ok(buf) => serde_json::from_slice(&buf)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HardState::default(),
+            Err(e) => return Err(Error::from(e)),
+        };
+
+        let path = dir.join(LOG_FILE);
+        let mut entries: Vec<Entry> = Vec::new();
+        if path.exists() {
+            let reader = BufReader::new(File::open(&path)?);
+            for entry in serde_json::Deserializer::from_reader(reader).into_iter::<Entry>() {
+                match entry {
+                    // last entry was cut short by a crash, it was never acknowledged
+                    Err(e) if e.is_eof() => break,
+                    entry => {
+                        let entry = entry?;
+                        if entry.index > hard.snapshot_index {
+                            entries.push(entry);
+                        }
+                    }
+                }
+            }
+        }
+
+        // a record cut short would be in the way of the next ones
+        let writer = rewrite(dir, &entries)?;
+        Ok(RaftLog {
+            dir: dir.to_owned(),
+            hard,
+            entries,
+            writer,
+        })
+    }
+
+    pub fn hard(&self) -> &HardState {
+        &self.hard
+    }
+
+    pub fn set_term(&mut self, term: u64, voted_for: Option<String>) -> Result<()> {
+        self.hard.term = term;
+        self.hard.voted_for = voted_for;
+        self.save_state()
+    }
+
+    /// members a new cluster starts with
+    pub fn bootstrap(&mut self, members: Vec<String>) -> Result<()> {
+        self.hard.members = members;
+        self.save_state()
+    }
+
+    pub fn snapshot_index(&self) -> u64 {
+        self.hard.snapshot_index
+    }
+
+    pub fn last_index(&self) -> u64 {
+        self.entries
+            .last()
+            .map_or(self.hard.snapshot_index, |entry| entry.index)
+    }
+
+    pub fn last_term(&self) -> u64 {
+        self.entries
+            .last()
+            .map_or(self.hard.snapshot_term, |entry| entry.term)
+    }
+
+    /// term of entry at `index`, `None` when it is not in log
+    pub fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.hard.snapshot_index {
+            return Some(self.hard.snapshot_term);
+        }
+        self.entry(index).map(|entry| entry.term)
+    }
+
+    pub fn entry(&self, index: u64) -> Option<&Entry> {
+        if index <= self.hard.snapshot_index {
+            return None;
+        }
+        self.entries
+            .get((index - self.hard.snapshot_index - 1) as usize)
+    }
+
+    /// up to `limit` entries starting at `index`
+    pub fn entries_from(&self, index: u64, limit: usize) -> Vec<Entry> {
+        let start = index.saturating_sub(self.hard.snapshot_index + 1) as usize;
+        self.entries
+            .iter()
+            .skip(start)
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    /// latest members, taking effect as soon as they are in log
+    pub fn members(&self) -> Vec<String> {
+        self.members_at(self.last_index())
+    }
+
+    /// members as of entry at `index`
+    pub fn members_at(&self, index: u64) -> Vec<String> {
+        self.entries
+            .iter()
+            .rev()
+            .filter(|entry| entry.index <= index)
+            .find_map(|entry| match &entry.payload {
+                Payload::Members(members) => Some(members.clone()),
+                _ => None,
+            })
+            .unwrap_or_else(|| self.hard.members.clone())
+    }
+
+    /// whether a change of members after `commit_index` is not committed yet
+    pub fn changing_members(&self, commit_index: u64) -> bool {
+        self.entries
+            .iter()
+            .any(|entry| entry.index > commit_index && matches!(entry.payload, Payload::Members(_)))
+    }
+
+    /// entries reach the disk before this returns, so they can be acknowledged
+    pub fn append(&mut self, entries: Vec<Entry>) -> Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        for entry in entries {
+            if entry.index != self.last_index() + 1 {
+                return Err(Error::from(ErrorKind::Storage(format!(
+                    "raft entry {} does not follow {}",
+                    entry.index,
+                    self.last_index()
+                ))));
+            }
+            serde_json::to_writer(&mut self.writer, &entry)?;
+            self.writer.write_all(b"\n")?;
+            self.entries.push(entry);
+        }
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    /// drop entries from `index` on, which conflict with the leader's
+    pub fn truncate(&mut self, index: u64) -> Result<()> {
+        let keep = index.saturating_sub(self.hard.snapshot_index + 1) as usize;
+        self.entries.truncate(keep);
+        self.writer = rewrite(&self.dir, &self.entries)?;
+        Ok(())
+    }
+
+    /// drop entries up to `index`, whose writes the store holds
+    pub fn compact(&mut self, index: u64) -> Result<()> {
+        let term = self.term_at(index).unwrap_or(self.hard.snapshot_term);
+        let members = self.members_at(index);
+        self.entries.retain(|entry| entry.index > index);
+        self.hard.snapshot_index = index;
+        self.hard.snapshot_term = term;
+        self.hard.members = members;
+        // state first, so entries left in old log are skipped on restart
+        self.save_state()?;
+        self.writer = rewrite(&self.dir, &self.entries)?;
+        Ok(())
+    }
+
+    /// drop every entry, the store was replaced by a snapshot up to `index`
+    pub fn reset(&mut self, index: u64, term: u64, members: Vec<String>) -> Result<()> {
+        self.entries.clear();
+        self.hard.snapshot_index = index;
+        self.hard.snapshot_term = term;
+        self.hard.members = members;
+        self.save_state()?;
+        self.writer = rewrite(&self.dir, &self.entries)?;
+        Ok(())
+    }
+
+    fn save_state(&self) -> Result<()> {
+        let tmp = self.dir.join("state.tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&serde_json::to_vec(&self.hard)?)?;
+        file.sync_all()?;
+        fs::rename(&tmp, self.dir.join(STATE_FILE))?;
+        sync_dir(&self.dir)
+    }
+}
+
+// write `entries` to a new file replacing log, returning a writer appending to it
+fn rewrite(dir: &Path, entries: &[Entry]) -> Result<BufWriter<File>> {
+    let tmp = dir.join("log.tmp");
+    let mut writer = BufWriter::new(File::create(&tmp)?);
+    for entry in entries {
+        serde_json::to_writer(&mut writer, entry)?;
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()?;
+    writer.get_ref().sync_data()?;
+    fs::rename(&tmp, dir.join(LOG_FILE))?;
+    sync_dir(dir)?;
+
+    let file = OpenOptions::new().append(true).open(dir.join(LOG_FILE))?;
+    Ok(BufWriter::new(file))
+}
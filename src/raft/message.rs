@@ -0,0 +1,64 @@
+use crate::common::Command;
+use serde::{Deserialize, Serialize};
+
+/// A record of the raft log
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub term: u64,
+    pub index: u64,
+    pub payload: Payload,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Payload {
+    /// appended by a new leader, so entries of earlier terms get committed
+    Noop {},
+    /// a write, numbered by index of its entry
+    Write(Command),
+    /// members of the cluster from this entry on
+    Members(Vec<String>),
+}
+
+/// Messages nodes of a cluster send each other, and their answers
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum RaftMessage {
+    RequestVote {
+        term: u64,
+        candidate: String,
+        last_log_index: u64,
+        last_log_term: u64,
+    },
+    Vote {
+        term: u64,
+        granted: bool,
+    },
+    /// entries following `prev_log_index`, none for a heartbeat
+    AppendEntries {
+        term: u64,
+        leader: String,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<Entry>,
+        leader_commit: u64,
+    },
+    /// on success `match_index` is the last entry follower has in common with leader,
+    /// on failure the last one it may have
+    Appended {
+        term: u64,
+        success: bool,
+        match_index: u64,
+    },
+    /// every pair stored by leader after applying entries up to `last_index`
+    InstallSnapshot {
+        term: u64,
+        leader: String,
+        last_index: u64,
+        last_term: u64,
+        members: Vec<String>,
+        pairs: Vec<(String, String)>,
+    },
+    Installed {
+        term: u64,
+        last_index: u64,
+    },
+}
@@ -0,0 +1,18 @@
+//! Raft consensus between `kvs-server` nodes.
+//!
+//! Every write goes to the leader, which appends it to its log and answers once
+//! a majority of members have stored it. Followers redirect clients to the leader
+//! with a `NotLeader` error, `ClusterClient` follows such redirects.
+//!
+//! Log entries are numbered like the store's writes, so a store knows the last entry
+//! it applied after a restart. Applied entries are dropped from the log after a while,
+//! members which fall behind them are sent a snapshot of the store instead.
+
+mod client;
+mod log;
+mod message;
+mod node;
+
+pub use self::client::ClusterClient;
+pub use self::message::{Entry, Payload, RaftMessage};
+pub use self::node::{RaftNode, Role};
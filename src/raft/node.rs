@@ -0,0 +1,902 @@
+use super::log::RaftLog;
+use super::message::{Entry, Payload, RaftMessage};
+use crate::client::Client;
+use crate::common::{Command, KvsEngine};
+use crate::error::{Error, ErrorKind, Result};
+use rand::Rng;
+use slog::{error, info, Logger};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// how often a node checks whether to start an election
+const TICK: Duration = Duration::from_millis(10);
+// leader sends entries, or an empty message, to each member this often
+const HEARTBEAT: Duration = Duration::from_millis(50);
+// followers hearing from no leader for a random time in this range start an election
+const ELECTION_TIMEOUT_MS: (u64, u64) = (300, 600);
+// how long to wait for another node to answer
+const RPC_TIMEOUT: Duration = Duration::from_millis(500);
+// most entries sent in one message
+const MAX_ENTRIES: usize = 256;
+// applied entries kept in log before the store is used as snapshot instead
+const SNAPSHOT_ENTRIES: u64 = 1024;
+// how long a client waits for its writes to be committed
+const COMMIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// A member of a raft cluster, keeping `engine` in step with the other members.
+/// Nodes are known by the address they serve clients on.
+pub struct RaftNode<T: KvsEngine> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T: KvsEngine> Clone for RaftNode<T> {
+    fn clone(&self) -> Self {
+        RaftNode {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+struct Inner<T: KvsEngine> {
+    id: String,
+    state: Mutex<State<T>>,
+    // signalled when entries are appended or applied, or role changes
+    changed: Condvar,
+    stopped: AtomicBool,
+}
+
+struct State<T: KvsEngine> {
+    engine: T,
+    log: RaftLog,
+    role: Role,
+    leader: Option<String>,
+    commit_index: u64,
+    last_applied: u64,
+    election_deadline: Instant,
+    votes: HashSet<String>,
+    // leader only, next entry to send and last one known to be stored by each member
+    next_index: HashMap<String, u64>,
+    match_index: HashMap<String, u64>,
+    // members a thread is sending entries to
+    replicating: HashSet<String>,
+    // members a snapshot is being built for, outside the lock
+    snapshotting: HashSet<String>,
+    // leader only, when the last message each member answered in this term was sent
+    acked: HashMap<String, Instant>,
+    auth: Option<(String, String)>,
+}
+
+impl<T: KvsEngine> RaftNode<T> {
+    /// Open node serving at `addr`, keeping its log in `dir`.
+    /// A new cluster starts with `members`, a node started without them
+    /// waits until a leader adds it.
+    pub fn open(addr: SocketAddr, dir: &Path, engine: T, members: &[SocketAddr]) -> Result<Self> {
+        let mut log = RaftLog::open(dir)?;
+        if engine.last_seq() > log.last_index() {
+            return Err(Error::from(ErrorKind::Storage(
+                "store has writes missing from raft log, start cluster with an empty store"
+                    .to_string(),
+            )));
+        }
+        if log.last_index() == 0 && log.members().is_empty() && !members.is_empty() {
+            log.bootstrap(members.iter().map(|addr| addr.to_string()).collect())?;
+        }
+
+        // the store holds every entry up to snapshot, later ones are applied again once
+        // committed, and skipped by the store if it has them
+        let applied = log.snapshot_index();
+        let state = State {
+            engine,
+            log,
+            role: Role::Follower,
+            leader: None,
+            commit_index: applied,
+            last_applied: applied,
+            election_deadline: election_deadline(),
+            votes: HashSet::new(),
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            replicating: HashSet::new(),
+            snapshotting: HashSet::new(),
+            acked: HashMap::new(),
+            auth: None,
+        };
+        Ok(RaftNode {
+            inner: Arc::new(Inner {
+                id: addr.to_string(),
+                state: Mutex::new(state),
+                changed: Condvar::new(),
+                stopped: AtomicBool::new(false),
+            }),
+        })
+    }
+
+    /// log in to other members as `user`, who must be allowed to write every key
+    pub fn with_auth(self, user: String, password: String) -> Self {
+        self.inner.lock().auth = Some((user, password));
+        self
+    }
+
+    pub fn id(&self) -> &str {
+        &self.inner.id
+    }
+
+    pub fn role(&self) -> Role {
+        self.inner.lock().role
+    }
+
+    /// address of the leader, if node knows it
+    pub fn leader(&self) -> Option<String> {
+        self.inner.lock().leader.clone()
+    }
+
+    pub fn term(&self) -> u64 {
+        self.inner.lock().log.hard().term
+    }
+
+    pub fn members(&self) -> Vec<String> {
+        self.inner.lock().log.members()
+    }
+
+    /// index of the last entry applied to the store
+    pub fn last_applied(&self) -> u64 {
+        self.inner.lock().last_applied
+    }
+
+    /// start taking part in elections, and sending entries while leading
+    pub fn start(&self, logger: &Logger) {
+        let inner = Arc::clone(&self.inner);
+        let logger = logger.clone();
+        thread::spawn(move || inner.tick(&logger));
+    }
+
+    /// stop every thread of node, it no longer leads or votes
+    pub fn stop(&self) {
+        self.inner.stopped.store(true, Ordering::SeqCst);
+        let mut state = self.inner.lock();
+        state.role = Role::Follower;
+        state.leader = None;
+        self.inner.changed.notify_all();
+    }
+
+    /// fail with the leader's address unless this node leads
+    pub fn check_leader(&self) -> Result<()> {
+        self.inner.check_leader(&self.inner.lock())
+    }
+
+    /// Fail unless most members still follow this node as leader, then wait until
+    /// every write committed before the call is applied, so reads see them
+    pub fn confirm_leader(&self) -> Result<()> {
+        self.inner.confirm_leader(self.inner.lock())
+    }
+
+    /// answer a message from another node
+    pub fn handle(&self, message: RaftMessage) -> Result<RaftMessage> {
+        if self.inner.stopped.load(Ordering::SeqCst) {
+            return Err(Error::from(ErrorKind::Error(
+                "raft node stopped".to_string(),
+            )));
+        }
+        let mut state = self.inner.lock();
+        match message {
+            RaftMessage::RequestVote {
+                term,
+                candidate,
+                last_log_index,
+                last_log_term,
+            } => self
+                .inner
+                .vote(&mut state, term, candidate, last_log_index, last_log_term),
+            RaftMessage::AppendEntries {
+                term,
+                leader,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+            } => self.inner.append_entries(
+                &mut state,
+                term,
+                leader,
+                (prev_log_index, prev_log_term),
+                entries,
+                leader_commit,
+            ),
+            RaftMessage::InstallSnapshot {
+                term,
+                leader,
+                last_index,
+                last_term,
+                members,
+                pairs,
+            } => self.inner.install_snapshot(
+                &mut state,
+                term,
+                leader,
+                (last_index, last_term),
+                members,
+                pairs,
+            ),
+            message => Err(Error::invalid_command(format!(
+                "not a raft request: {:?}",
+                message
+            ))),
+        }
+    }
+
+    /// append writes to log, returning once they are applied
+    pub fn propose(&self, commands: Vec<Command>) -> Result<()> {
+        if commands.is_empty() {
+            return Ok(());
+        }
+        let mut state = self.inner.lock();
+        self.inner.check_leader(&state)?;
+        let payloads = commands.into_iter().map(Payload::Write).collect();
+        let index = self.inner.append(&mut state, payloads)?;
+        self.inner.wait_applied(state, index)
+    }
+
+    /// let node at `addr` join cluster, returning members after the change
+    pub fn add_member(&self, addr: String) -> Result<Vec<String>> {
+        self.change_members(|members| {
+            if !members.contains(&addr) {
+                members.push(addr);
+            }
+        })
+    }
+
+    pub fn remove_member(&self, addr: String) -> Result<Vec<String>> {
+        self.change_members(|members| members.retain(|member| *member != addr))
+    }
+
+    // one member joins or leaves at a time, so old and new majorities always overlap
+    fn change_members<F: FnOnce(&mut Vec<String>)>(&self, change: F) -> Result<Vec<String>> {
+        let mut state = self.inner.lock();
+        self.inner.check_leader(&state)?;
+        if state.log.changing_members(state.commit_index) {
+            return Err(Error::from(ErrorKind::Error(
+                "another change of members is in progress".to_string(),
+            )));
+        }
+        let mut members = state.log.members();
+        change(&mut members);
+        if members.is_empty() {
+            return Err(Error::invalid_command(
+                "cluster needs at least one member".to_string(),
+            ));
+        }
+        if members == state.log.members() {
+            return Ok(members);
+        }
+        let index = self
+            .inner
+            .append(&mut state, vec![Payload::Members(members.clone())])?;
+        self.inner.wait_applied(state, index)?;
+        Ok(members)
+    }
+}
+
+impl<T: KvsEngine> Inner<T> {
+    fn lock(&self) -> MutexGuard<'_, State<T>> {
+        self.state.lock().unwrap()
+    }
+
+    fn running(&self) -> bool {
+        !self.stopped.load(Ordering::SeqCst)
+    }
+
+    fn check_leader(&self, state: &State<T>) -> Result<()> {
+        if state.role == Role::Leader {
+            Ok(())
+        } else {
+            Err(Error::not_leader(state.leader.clone()))
+        }
+    }
+
+    // start elections when leader is silent, and keep a thread sending to each member
+    fn tick(self: &Arc<Self>, logger: &Logger) {
+        while self.running() {
+            {
+                let mut state = self.lock();
+                if state.role == Role::Leader {
+                    self.start_replicators(&mut state, logger);
+                } else if Instant::now() >= state.election_deadline
+                    && state.log.members().contains(&self.id)
+                {
+                    if let Err(e) = self.start_election(&mut state, logger) {
+                        error!(logger, "election failed"; "error" => format!("{}", e));
+                    }
+                }
+            }
+            thread::sleep(TICK);
+        }
+    }
+
+    fn start_election(self: &Arc<Self>, state: &mut State<T>, logger: &Logger) -> Result<()> {
+        let term = state.log.hard().term + 1;
+        state.log.set_term(term, Some(self.id.clone()))?;
+        state.role = Role::Candidate;
+        state.leader = None;
+        state.votes = vec![self.id.clone()].into_iter().collect();
+        state.election_deadline = election_deadline();
+        info!(logger, "starting election"; "term" => term);
+
+        let message = RaftMessage::RequestVote {
+            term,
+            candidate: self.id.clone(),
+            last_log_index: state.log.last_index(),
+            last_log_term: state.log.last_term(),
+        };
+        for member in state.log.members() {
+            if member == self.id {
+                continue;
+            }
+            let inner = Arc::clone(self);
+            let message = message.clone();
+            let logger = logger.clone();
+            let auth = state.auth.clone();
+            thread::spawn(move || {
+                let mut client = None;
+                if let Ok(answer) = call(&mut client, &member, &auth, message) {
+                    let mut state = inner.lock();
+                    inner.count_vote(&mut state, term, member, answer, &logger);
+                }
+            });
+        }
+        // a single member elects itself
+        self.check_votes(state, logger);
+        Ok(())
+    }
+
+    fn count_vote(
+        &self,
+        state: &mut State<T>,
+        term: u64,
+        member: String,
+        answer: RaftMessage,
+        logger: &Logger,
+    ) {
+        if let RaftMessage::Vote {
+            term: their_term,
+            granted,
+        } = answer
+        {
+            if self.step_down(state, their_term).is_err() {
+                return;
+            }
+            if granted && state.role == Role::Candidate && state.log.hard().term == term {
+                state.votes.insert(member);
+                self.check_votes(state, logger);
+            }
+        }
+    }
+
+    fn check_votes(&self, state: &mut State<T>, logger: &Logger) {
+        let members = state.log.members();
+        let votes = members
+            .iter()
+            .filter(|member| state.votes.contains(*member))
+            .count();
+        if state.role != Role::Candidate || votes * 2 <= members.len() {
+            return;
+        }
+
+        info!(logger, "elected leader"; "term" => state.log.hard().term, "votes" => votes);
+        state.role = Role::Leader;
+        state.leader = Some(self.id.clone());
+        state.next_index.clear();
+        state.match_index.clear();
+        state.acked.clear();
+        // entries of earlier terms are committed along with one of this term
+        if let Err(e) = self.append(state, vec![Payload::Noop {}]) {
+            error!(logger, "can not append to raft log"; "error" => format!("{}", e));
+        }
+    }
+
+    // follow anyone with a newer term
+    fn step_down(&self, state: &mut State<T>, term: u64) -> Result<()> {
+        if term > state.log.hard().term {
+            state.log.set_term(term, None)?;
+            state.role = Role::Follower;
+            state.leader = None;
+            state.votes.clear();
+            self.changed.notify_all();
+        }
+        Ok(())
+    }
+
+    fn vote(
+        &self,
+        state: &mut State<T>,
+        term: u64,
+        candidate: String,
+        last_log_index: u64,
+        last_log_term: u64,
+    ) -> Result<RaftMessage> {
+        self.step_down(state, term)?;
+        let current = state.log.hard().term;
+        // candidate must have every entry this node might have helped commit
+        let up_to_date =
+            (last_log_term, last_log_index) >= (state.log.last_term(), state.log.last_index());
+        let free = match &state.log.hard().voted_for {
+            Some(voted_for) => *voted_for == candidate,
+            None => true,
+        };
+        let granted = term == current && up_to_date && free;
+        if granted {
+            state.log.set_term(term, Some(candidate))?;
+            state.election_deadline = election_deadline();
+        }
+        Ok(RaftMessage::Vote {
+            term: current,
+            granted,
+        })
+    }
+
+    fn append_entries(
+        &self,
+        state: &mut State<T>,
+        term: u64,
+        leader: String,
+        (prev_log_index, prev_log_term): (u64, u64),
+        entries: Vec<Entry>,
+        leader_commit: u64,
+    ) -> Result<RaftMessage> {
+        let current = state.log.hard().term;
+        let refuse = |match_index| RaftMessage::Appended {
+            term: current.max(term),
+            success: false,
+            match_index,
+        };
+        if term < current {
+            return Ok(refuse(0));
+        }
+        self.follow(state, term, leader)?;
+
+        if prev_log_index > state.log.last_index() {
+            return Ok(refuse(state.log.last_index()));
+        }
+        // entries up to snapshot were committed, so they match leader's
+        if prev_log_index > state.log.snapshot_index()
+            && state.log.term_at(prev_log_index) != Some(prev_log_term)
+        {
+            return Ok(refuse(prev_log_index - 1));
+        }
+
+        let mut last_new = prev_log_index;
+        let mut new = Vec::new();
+        for entry in entries {
+            last_new = entry.index;
+            if entry.index <= state.log.snapshot_index() {
+                continue;
+            }
+            match state.log.term_at(entry.index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => state.log.truncate(entry.index)?,
+                None => {}
+            }
+            new.push(entry);
+        }
+        state.log.append(new)?;
+
+        if leader_commit > state.commit_index {
+            state.commit_index = state.commit_index.max(leader_commit.min(last_new));
+            self.apply(state)?;
+        }
+        Ok(RaftMessage::Appended {
+            term,
+            success: true,
+            match_index: last_new,
+        })
+    }
+
+    fn install_snapshot(
+        &self,
+        state: &mut State<T>,
+        term: u64,
+        leader: String,
+        (last_index, last_term): (u64, u64),
+        members: Vec<String>,
+        pairs: Vec<(String, String)>,
+    ) -> Result<RaftMessage> {
+        let current = state.log.hard().term;
+        if term < current {
+            return Ok(RaftMessage::Installed {
+                term: current,
+                last_index: 0,
+            });
+        }
+        self.follow(state, term, leader)?;
+
+        // an old snapshot, this node has moved on already
+        if last_index > state.commit_index {
+            state.engine.restore(last_index, pairs)?;
+            state.log.reset(last_index, last_term, members)?;
+            state.commit_index = last_index;
+            state.last_applied = last_index;
+            self.changed.notify_all();
+        }
+        Ok(RaftMessage::Installed { term, last_index })
+    }
+
+    // accept `leader` of `term`, which is not older than current one
+    fn follow(&self, state: &mut State<T>, term: u64, leader: String) -> Result<()> {
+        self.step_down(state, term)?;
+        if state.role != Role::Follower {
+            state.role = Role::Follower;
+            self.changed.notify_all();
+        }
+        state.leader = Some(leader);
+        state.election_deadline = election_deadline();
+        Ok(())
+    }
+
+    // append payloads as leader, returning index of the last one
+    fn append(&self, state: &mut State<T>, payloads: Vec<Payload>) -> Result<u64> {
+        let term = state.log.hard().term;
+        let mut index = state.log.last_index();
+        let entries = payloads
+            .into_iter()
+            .map(|payload| {
+                index += 1;
+                let payload = match payload {
+                    Payload::Write(command) => Payload::Write(command.with_seq(index)),
+                    payload => payload,
+                };
+                Entry {
+                    term,
+                    index,
+                    payload,
+                }
+            })
+            .collect();
+        state.log.append(entries)?;
+        self.changed.notify_all();
+        self.advance_commit(state)?;
+        Ok(index)
+    }
+
+    // wait for entry at `index`, appended by this leader, to be applied
+    fn wait_applied(&self, mut state: MutexGuard<'_, State<T>>, index: u64) -> Result<()> {
+        let term = state.log.hard().term;
+        let deadline = Instant::now() + COMMIT_TIMEOUT;
+        loop {
+            if state.log.hard().term == term && state.last_applied >= index {
+                return Ok(());
+            }
+            if state.log.hard().term != term || state.role != Role::Leader {
+                // entry may still be committed by next leader, or overwritten
+                return Err(Error::not_leader(state.leader.clone()));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::from(ErrorKind::Error(
+                    "write was not committed in time, most members may be down".to_string(),
+                )));
+            }
+            state = self.changed.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+
+    // Read index: leader commits an entry of its term, a majority answers
+    // messages sent after the call, then entries committed by then are applied
+    fn confirm_leader(&self, mut state: MutexGuard<'_, State<T>>) -> Result<()> {
+        self.check_leader(&state)?;
+        let term = state.log.hard().term;
+        let asked = Instant::now();
+        let deadline = asked + COMMIT_TIMEOUT;
+        let mut read_index = None;
+        // replicators send now instead of at next heartbeat
+        self.changed.notify_all();
+        loop {
+            if state.log.hard().term != term || state.role != Role::Leader {
+                return Err(Error::not_leader(state.leader.clone()));
+            }
+            if read_index.is_none() && state.log.term_at(state.commit_index) == Some(term) {
+                read_index = Some(state.commit_index);
+            }
+            let members = state.log.members();
+            let confirmed = members
+                .iter()
+                .filter(|member| {
+                    **member == self.id
+                        || state.acked.get(*member).is_some_and(|sent| *sent >= asked)
+                })
+                .count();
+            if let Some(index) = read_index {
+                if confirmed * 2 > members.len() && state.last_applied >= index {
+                    return Ok(());
+                }
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::from(ErrorKind::Error(
+                    "leadership was not confirmed in time, most members may be down".to_string(),
+                )));
+            }
+            state = self.changed.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+
+    // commit latest entry of this term stored by a majority
+    fn advance_commit(&self, state: &mut State<T>) -> Result<()> {
+        if state.role != Role::Leader {
+            return Ok(());
+        }
+        let members = state.log.members();
+        let term = state.log.hard().term;
+        for index in (state.commit_index + 1..=state.log.last_index()).rev() {
+            if state.log.term_at(index) != Some(term) {
+                break;
+            }
+            let stored = members
+                .iter()
+                .filter(|member| {
+                    if **member == self.id {
+                        true
+                    } else {
+                        state.match_index.get(*member).copied().unwrap_or(0) >= index
+                    }
+                })
+                .count();
+            if stored * 2 > members.len() {
+                state.commit_index = index;
+                break;
+            }
+        }
+        self.apply(state)
+    }
+
+    // apply committed entries to store
+    fn apply(&self, state: &mut State<T>) -> Result<()> {
+        while state.last_applied < state.commit_index {
+            let index = state.last_applied + 1;
+            let entry = state.log.entry(index).cloned().ok_or_else(|| {
+                Error::from(ErrorKind::Storage(format!(
+                    "committed raft entry {} is missing",
+                    index
+                )))
+            })?;
+            if let Payload::Write(command) = entry.payload {
+                state.engine.apply(vec![command])?;
+            }
+            state.last_applied = index;
+        }
+        self.changed.notify_all();
+
+        // a leader taken out of cluster hands over once that is committed
+        if state.role == Role::Leader
+            && !state.log.members_at(state.commit_index).contains(&self.id)
+        {
+            state.role = Role::Follower;
+            state.leader = None;
+        }
+
+        // entries are kept while a snapshot is built, its index is looked up after
+        if state.snapshotting.is_empty()
+            && state.last_applied - state.log.snapshot_index() > SNAPSHOT_ENTRIES
+        {
+            // entries are dropped, store must keep their writes
+            state.engine.flush()?;
+            let index = state.last_applied;
+            state.log.compact(index)?;
+        }
+        Ok(())
+    }
+
+    fn start_replicators(self: &Arc<Self>, state: &mut State<T>, logger: &Logger) {
+        let term = state.log.hard().term;
+        let next = state.log.last_index() + 1;
+        for member in state.log.members() {
+            if member == self.id || state.replicating.contains(&member) {
+                continue;
+            }
+            state.replicating.insert(member.clone());
+            state.next_index.entry(member.clone()).or_insert(next);
+            let inner = Arc::clone(self);
+            let logger = logger.clone();
+            thread::spawn(move || inner.replicate(member, term, &logger));
+        }
+    }
+
+    // send entries to `member` while leading in `term`
+    fn replicate(&self, member: String, term: u64, logger: &Logger) {
+        let mut client = None;
+        loop {
+            let (message, auth) = {
+                let mut state = self.lock();
+                if !self.running()
+                    || state.role != Role::Leader
+                    || state.log.hard().term != term
+                    || !state.log.members().contains(&member)
+                {
+                    state.replicating.remove(&member);
+                    return;
+                }
+                let message = match self.message_for(&state, &member, term) {
+                    Some(message) => Ok(message),
+                    None => {
+                        // reading the whole store takes a while, other members are served meanwhile
+                        let applied = state.last_applied;
+                        state.snapshotting.insert(member.clone());
+                        let engine = state.engine.clone();
+                        drop(state);
+                        let snapshot = engine.snapshot();
+                        state = self.lock();
+                        state.snapshotting.remove(&member);
+                        if state.role != Role::Leader || state.log.hard().term != term {
+                            continue;
+                        }
+                        // snapshot holds writes up to `seq`, which is past `applied` only
+                        // when writes were applied while it was built
+                        snapshot.map(|(seq, pairs)| {
+                            self.snapshot_message(&state, term, seq.max(applied), pairs)
+                        })
+                    }
+                };
+                match message {
+                    Ok(message) => (message, state.auth.clone()),
+                    Err(e) => {
+                        error!(logger, "can not build raft message"; "error" => format!("{}", e));
+                        drop(self.changed.wait_timeout(state, HEARTBEAT).unwrap());
+                        continue;
+                    }
+                }
+            };
+
+            let sent = Instant::now();
+            let answer = call(&mut client, &member, &auth, message);
+
+            let mut state = self.lock();
+            match answer {
+                Ok(answer) => {
+                    if let Err(e) = self.replicated(&mut state, &member, term, sent, answer) {
+                        error!(logger, "can not update raft log"; "error" => format!("{}", e));
+                    }
+                }
+                Err(_) => {
+                    // member is down, try again after a while
+                    client = None;
+                    drop(self.changed.wait_timeout(state, HEARTBEAT).unwrap());
+                    continue;
+                }
+            }
+            // keep sending while member lags behind, otherwise wait for new entries
+            if state.next_index.get(&member).copied().unwrap_or(0) > state.log.last_index() {
+                drop(self.changed.wait_timeout(state, HEARTBEAT).unwrap());
+            }
+        }
+    }
+
+    // entries `member` needs next, `None` when they are gone and it needs a snapshot
+    fn message_for(&self, state: &State<T>, member: &str, term: u64) -> Option<RaftMessage> {
+        let next = state
+            .next_index
+            .get(member)
+            .copied()
+            .unwrap_or_else(|| state.log.last_index() + 1);
+        if next <= state.log.snapshot_index() {
+            return None;
+        }
+        let prev_log_index = next - 1;
+        Some(RaftMessage::AppendEntries {
+            term,
+            leader: self.id.clone(),
+            prev_log_index,
+            prev_log_term: state.log.term_at(prev_log_index).unwrap_or(0),
+            entries: state.log.entries_from(next, MAX_ENTRIES),
+            leader_commit: state.commit_index,
+        })
+    }
+
+    // store holding every write up to `last_index`, sent instead of entries
+    fn snapshot_message(
+        &self,
+        state: &State<T>,
+        term: u64,
+        last_index: u64,
+        pairs: Vec<(String, String)>,
+    ) -> RaftMessage {
+        RaftMessage::InstallSnapshot {
+            term,
+            leader: self.id.clone(),
+            last_index,
+            last_term: state.log.term_at(last_index).unwrap_or(0),
+            members: state.log.members_at(last_index),
+            pairs,
+        }
+    }
+
+    fn replicated(
+        &self,
+        state: &mut State<T>,
+        member: &str,
+        term: u64,
+        sent: Instant,
+        answer: RaftMessage,
+    ) -> Result<()> {
+        let their_term = match &answer {
+            RaftMessage::Appended { term, .. } | RaftMessage::Installed { term, .. } => *term,
+            answer => {
+                return Err(Error::invalid_command(format!(
+                    "unexpected raft answer: {:?}",
+                    answer
+                )))
+            }
+        };
+        self.step_down(state, their_term)?;
+        if state.role != Role::Leader || state.log.hard().term != term {
+            return Ok(());
+        }
+        // member follows this leader, whether or not it took the entries
+        state.acked.insert(member.to_owned(), sent);
+        self.changed.notify_all();
+
+        let matched = match answer {
+            RaftMessage::Appended {
+                success: true,
+                match_index,
+                ..
+            }
+            | RaftMessage::Installed {
+                last_index: match_index,
+                ..
+            } => match_index,
+            RaftMessage::Appended { match_index, .. } => {
+                // go back to the last entry member may have in common with leader
+                let next = state.next_index.get(member).copied().unwrap_or(1);
+                let next = next.saturating_sub(1).min(match_index + 1).max(1);
+                state.next_index.insert(member.to_owned(), next);
+                return Ok(());
+            }
+            _ => return Ok(()),
+        };
+        let known = state.match_index.get(member).copied().unwrap_or(0);
+        state
+            .match_index
+            .insert(member.to_owned(), known.max(matched));
+        state.next_index.insert(member.to_owned(), matched + 1);
+        self.advance_commit(state)
+    }
+}
+
+// send `message` to `member`, connecting first unless `client` is connected already
+fn call(
+    client: &mut Option<Client<'static>>,
+    member: &str,
+    auth: &Option<(String, String)>,
+    message: RaftMessage,
+) -> Result<RaftMessage> {
+    if client.is_none() {
+        let addr: SocketAddr = member
+            .parse()
+            .map_err(|_| Error::invalid_command(format!("invalid member address {}", member)))?;
+        let mut connected = Client::connect_timeout(addr, RPC_TIMEOUT)?;
+        if let Some((user, password)) = auth {
+            connected.auth(user.to_owned(), password.to_owned())?;
+        }
+        *client = Some(connected);
+    }
+    let result = client.as_mut().unwrap().raft(message);
+    if result.is_err() {
+        *client = None;
+    }
+    result
+}
+
+fn election_deadline() -> Instant {
+    let (min, max) = ELECTION_TIMEOUT_MS;
+    Instant::now() + Duration::from_millis(rand::thread_rng().gen_range(min..max))
+}
@@ -0,0 +1,83 @@
+use crate::client::Client;
+use crate::common::KvsEngine;
+use crate::error::Result;
+use crate::net::Replication;
+use crate::server::ShutdownHandle;
+use slog::{error, info, Logger};
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+
+// how long to wait before asking an up to date leader again
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+// how long to wait before reconnecting to a leader which failed
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Keeps a store in step with a leader server,
+/// by applying every write the leader makes in the same order
+pub struct Follower<T: KvsEngine> {
+    engine: T,
+    leader: SocketAddr,
+    auth: Option<(String, String)>,
+}
+
+impl<T: KvsEngine> Follower<T> {
+    pub fn new(engine: T, leader: SocketAddr) -> Self {
+        Follower {
+            engine,
+            leader,
+            auth: None,
+        }
+    }
+
+    /// log in to leader as `user`, who must be allowed to read every key
+    pub fn with_auth(mut self, user: String, password: String) -> Self {
+        self.auth = Some((user, password));
+        self
+    }
+
+    pub fn leader(&self) -> SocketAddr {
+        self.leader
+    }
+
+    /// follow leader until `shutdown`, reconnecting whenever connection fails
+    pub fn run(&self, shutdown: &ShutdownHandle, logger: &Logger) {
+        while !shutdown.is_shutdown() {
+            if let Err(e) = self.follow(shutdown, logger) {
+                error!(logger, "replication failed"; "leader" => self.leader, "error" => format!("{}", e));
+                sleep_unless_shutdown(RETRY_INTERVAL, shutdown);
+            }
+        }
+    }
+
+    fn follow(&self, shutdown: &ShutdownHandle, logger: &Logger) -> Result<()> {
+        let mut client = Client::connect(self.leader)?;
+        if let Some((user, password)) = &self.auth {
+            client.auth(user.to_owned(), password.to_owned())?;
+        }
+        info!(logger, "following leader"; "leader" => self.leader, "seq" => self.engine.last_seq());
+
+        while !shutdown.is_shutdown() {
+            match client.replicate(self.engine.last_seq())? {
+                Replication::Changes(changes) if changes.is_empty() => {
+                    sleep_unless_shutdown(POLL_INTERVAL, shutdown)
+                }
+                Replication::Changes(changes) => self.engine.apply(changes)?,
+                Replication::Snapshot { seq, pairs } => {
+                    info!(logger, "restoring snapshot"; "seq" => seq, "keys" => pairs.len());
+                    self.engine.restore(seq, pairs)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn sleep_unless_shutdown(duration: Duration, shutdown: &ShutdownHandle) {
+    let step = POLL_INTERVAL.min(duration);
+    let mut slept = Duration::from_secs(0);
+    while slept < duration && !shutdown.is_shutdown() {
+        thread::sleep(step);
+        slept += step;
+    }
+}
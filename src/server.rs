@@ -1,89 +1,798 @@
-use crate::common::KvsEngine;
-use crate::error::Result;
-use crate::net::{Request, Response};
-use crate::thread_pool::ThreadPool;
-use serde_json::Deserializer;
+use crate::auth::{User, Users};
+use crate::common::{Command, KvsEngine};
+use crate::error::{Error, ErrorKind, Result};
+use crate::net::{
+    Handshake, Replication, Request, Response, ServerError, FEATURES, MIN_PROTOCOL_VERSION,
+    PROTOCOL_VERSION,
+};
+use crate::raft::RaftNode;
+use crate::replication::Follower;
+use crate::stream::Stream;
+use crate::thread_pool::{PoolStats, ThreadPool};
+use rustls::ServerConfig;
+use serde::{Deserialize, Serialize};
+use serde_json::{Deserializer, Value};
 use slog::{error, info, o, Logger};
-use std::io::{BufReader, BufWriter, Write};
-use std::net::{SocketAddr, TcpListener, TcpStream};
-use std::sync::Arc;
+use std::cell::Cell;
+use std::collections::HashMap;
+#[cfg(unix)]
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// how often shutdown checks whether connections are done
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+// most writes sent to a follower at once
+const REPLICATION_BATCH: usize = 1024;
+// most pairs sent for one scan request
+const SCAN_LIMIT: usize = 1024;
+
 pub struct Server<T: KvsEngine, U: ThreadPool> {
     engine: T,
-    pool: U,
+    pool: Arc<U>,
+    tls: Option<Arc<ServerConfig>>,
+    users: Option<Arc<Users>>,
+    shutdown: ShutdownHandle,
+    // how long to wait for in-flight requests on shutdown
+    shutdown_timeout: Duration,
+    connections: Connections,
+    max_connections: Option<usize>,
+    // close connections which send nothing for this long
+    idle_timeout: Option<Duration>,
+    max_request_size: Option<usize>,
+    stats: StatsSource,
+    // started when serving begins
+    follower: Option<Follower<T>>,
+    // writes are refused while following this leader
+    leader: Option<SocketAddr>,
+    // member of a raft cluster, started when serving begins
+    raft: Option<RaftNode<T>>,
+    // backups go under this directory, refused without it
+    backup_dir: Option<Arc<PathBuf>>,
 }
 
-impl<T: KvsEngine, U: ThreadPool> Server<T, U> {
+/// Numbers reported by `Stats` request
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServerStats {
+    pub connections: usize,
+    pub pool: PoolStats,
+}
+
+// lets sessions read stats without owning pool
+type StatsSource = Arc<dyn Fn() -> ServerStats + Send + Sync>;
+
+impl<T: KvsEngine, U: ThreadPool + Send + Sync + 'static> Server<T, U> {
     pub fn new(engine: T, pool: U) -> Self {
-        Server { engine, pool }
+        let pool = Arc::new(pool);
+        let connections = Connections::default();
+        // weak, so a session finishing last never drops pool on one of its own threads
+        let stats: StatsSource = {
+            let pool = Arc::downgrade(&pool);
+            let connections = connections.clone();
+            Arc::new(move || ServerStats {
+                connections: connections.len(),
+                pool: pool.upgrade().map(|pool| pool.stats()).unwrap_or_default(),
+            })
+        };
+        Server {
+            engine,
+            pool,
+            stats,
+            tls: None,
+            users: None,
+            shutdown: ShutdownHandle::default(),
+            shutdown_timeout: Duration::from_secs(30),
+            connections,
+            max_connections: None,
+            idle_timeout: None,
+            max_request_size: None,
+            follower: None,
+            leader: None,
+            raft: None,
+            backup_dir: None,
+        }
+    }
+
+    /// accept only tls connections on tcp, see `tls::server_config`
+    pub fn with_tls(mut self, config: Arc<ServerConfig>) -> Self {
+        self.tls = Some(config);
+        self
+    }
+
+    /// require clients to authenticate as one of `users`,
+    /// and only let them touch keys their rules allow
+    pub fn with_users(mut self, users: Users) -> Self {
+        self.users = Some(Arc::new(users));
+        self
+    }
+
+    /// let clients back up the store into directories under `dir`
+    pub fn with_backup_dir(mut self, dir: PathBuf) -> Self {
+        self.backup_dir = Some(Arc::new(dir));
+        self
+    }
+
+    /// how long shutdown waits for in-flight requests before closing connections
+    pub fn with_shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// turn away new clients with an overloaded error while `max` are connected
+    pub fn with_max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    /// close connections waiting longer than `timeout` for next request
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// reject requests larger than `size` bytes and close their connection
+    pub fn with_max_request_size(mut self, size: usize) -> Self {
+        self.max_request_size = Some(size);
+        self
+    }
+
+    /// replicate writes from `follower`'s leader while serving,
+    /// and refuse writes from clients with a read only error
+    pub fn with_follower(mut self, follower: Follower<T>) -> Self {
+        self.leader = Some(follower.leader());
+        self.follower = Some(follower);
+        self
+    }
+
+    /// serve as member `node` of a raft cluster: writes go through its log,
+    /// and clients of a node not leading are told where the leader is
+    pub fn with_raft(mut self, node: RaftNode<T>) -> Self {
+        self.raft = Some(node);
+        self
+    }
+
+    /// handle to stop serving from another thread
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
     }
 
     pub fn serve(&mut self, addr: &SocketAddr, logger: Logger) -> Result<()> {
         let listener = TcpListener::bind(addr)?;
-        let logger = Arc::new(logger);
+        self.shutdown
+            .wake_at(Listening::Tcp(listener.local_addr()?));
+        let incoming = listener.incoming().map(|stream| stream.map(Stream::Tcp));
+        self.accept(incoming, logger)
+    }
+
+    /// serve clients on a unix domain socket at `path`.
+    /// A stale socket file left at `path` is replaced, and tls is never used.
+    #[cfg(unix)]
+    pub fn serve_unix(&mut self, path: &Path, logger: Logger) -> Result<()> {
+        if fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        self.shutdown.wake_at(Listening::Unix(path.to_owned()));
+        let incoming = listener.incoming().map(|stream| stream.map(Stream::Unix));
+        self.accept(incoming, logger)?;
+        fs::remove_file(path)?;
+        Ok(())
+    }
 
-        for stream in listener.incoming() {
-            if let Ok(stream) = stream {
-                let peer_addr = stream.peer_addr()?;
-                let engine = self.engine.clone();
-                let logger = Arc::clone(&logger);
+    // serve connections until shutdown is requested
+    fn accept<I>(&mut self, mut incoming: I, logger: Logger) -> Result<()>
+    where
+        I: Iterator<Item = io::Result<Stream>>,
+    {
+        let logger = Arc::new(logger);
+        let follower = self.follower.take().map(|follower| {
+            let shutdown = self.shutdown.clone();
+            let logger = logger.new(o!("leader" => follower.leader()));
+            thread::spawn(move || follower.run(&shutdown, &logger))
+        });
+        if let Some(raft) = &self.raft {
+            raft.start(&logger.new(o!("raft" => raft.id().to_owned())));
+        }
 
-                self.pool.execute(move || {
-                    let logger = logger.new(o!("peer_address"=>peer_addr));
-                    if let Err(e) = handle_client(engine, stream, &logger) {
-                        error!(logger, "Error on server"; "error" => format!("{}",e));
+        // shutdown connects to the listener to wake it, that connection is not served
+        while !self.shutdown.is_shutdown() {
+            match incoming.next() {
+                Some(Ok(stream)) => {
+                    if let Err(e) = self.dispatch(stream, &logger) {
+                        error!(logger, "Error dispatching connection"; "error" => format!("{}", e));
                     }
-                })?;
-            } else {
-                error!(logger, "Error connection");
+                }
+                Some(Err(e)) => error!(logger, "Error connection"; "error" => format!("{}", e)),
+                None => break,
             }
         }
 
+        // follower sees shutdown too, wait for its last writes before flushing
+        if let Some(follower) = follower {
+            let _ = follower.join();
+        }
+        // a node shutting down no longer leads or votes
+        if let Some(raft) = &self.raft {
+            raft.stop();
+        }
+        self.drain(&logger)
+    }
+
+    // handle a new connection in thread pool
+    fn dispatch(&mut self, stream: Stream, logger: &Arc<Logger>) -> Result<()> {
+        stream.set_read_timeout(self.idle_timeout)?;
+        if let Some(max) = self.max_connections {
+            if self.connections.len() >= max {
+                return self.reject(
+                    stream,
+                    format!("server already has {} clients", max),
+                    logger,
+                );
+            }
+        }
+
+        let peer_addr = stream.peer();
+        let session = Session::new(
+            self.engine.clone(),
+            self.users.clone(),
+            Arc::clone(&self.stats),
+            self.leader,
+            self.raft.clone(),
+            self.backup_dir.clone(),
+        );
+        let job_logger = Arc::clone(logger);
+        let tls = self.tls.clone();
+        let max_request_size = self.max_request_size.unwrap_or(usize::MAX);
+        let guard = self.connections.add(stream.try_clone()?);
+        let rejected = stream.try_clone()?;
+
+        let result = self.pool.try_execute(move || {
+            // deregister connection when done, even if handler panics
+            let _guard = guard;
+            let logger = job_logger.new(o!("peer_address"=>peer_addr));
+            let stream = match (tls, stream) {
+                (Some(config), Stream::Tcp(stream)) => Stream::tls_server(stream, config),
+                (_, stream) => Ok(stream),
+            };
+            let result =
+                stream.and_then(|stream| handle_client(session, stream, max_request_size, &logger));
+            if let Err(e) = result {
+                error!(logger, "Error on server"; "error" => format!("{}",e));
+            }
+        });
+
+        match result {
+            Err(e) => match e.kind() {
+                ErrorKind::Overloaded(message) => {
+                    let message = message.to_owned();
+                    self.reject(rejected, message, logger)
+                }
+                _ => Err(e),
+            },
+            ok => ok,
+        }
+    }
+
+    // turn a client away without taking a worker thread
+    fn reject(&self, mut stream: Stream, message: String, logger: &Logger) -> Result<()> {
+        error!(logger, "rejecting connection"; "reason" => &message, "peer_address" => stream.peer());
+        // tls clients can not read a plain response, they only see connection closed
+        if self.tls.is_none() {
+            let mut writer = BufWriter::new(stream.try_clone()?);
+            let response = Response::Error(ServerError::Overloaded(message));
+            // client may be gone already
+            let _ = send_response(&mut writer, &response);
+        }
+        // drop what client has sent so far, so closing does not reset the connection
+        // before it reads the response
+        stream.set_nonblocking(true)?;
+        let _ = io::copy(&mut stream, &mut io::sink());
+        let _ = stream.shutdown(Shutdown::Both);
+        Ok(())
+    }
+
+    // let in-flight requests finish, then make sure written data is on disk
+    fn drain(&mut self, logger: &Logger) -> Result<()> {
+        info!(logger, "shutting down"; "connections" => self.connections.len());
+        // clients waiting for next request see end of stream,
+        // while requests being handled can still send responses
+        self.connections.shutdown(Shutdown::Read);
+
+        let deadline = Instant::now() + self.shutdown_timeout;
+        while !self.connections.is_empty() && Instant::now() < deadline {
+            thread::sleep(POLL_INTERVAL);
+        }
+        if !self.connections.is_empty() {
+            error!(logger, "closing connections still busy"; "connections" => self.connections.len());
+            self.connections.shutdown(Shutdown::Both);
+        }
+
+        self.engine.flush()?;
+        info!(logger, "server stopped");
         Ok(())
     }
 }
 
-fn send_response(writer: &mut BufWriter<&TcpStream>, response: &Response) -> Result<()> {
+/// Stops a running server.
+/// It stops accepting connections, waits for in-flight requests and flushes the engine.
+#[derive(Clone, Default)]
+pub struct ShutdownHandle {
+    shutdown: Arc<AtomicBool>,
+    // where the server listens, connecting there wakes its blocked accept
+    listening: Arc<Mutex<Option<Listening>>>,
+}
+
+enum Listening {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        // server may be gone already
+        match &*self.listening.lock().unwrap() {
+            Some(Listening::Tcp(addr)) => {
+                let _ = TcpStream::connect(reachable(addr));
+            }
+            #[cfg(unix)]
+            Some(Listening::Unix(path)) => {
+                let _ = UnixStream::connect(path);
+            }
+            None => {}
+        }
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.shutdown.load(Ordering::SeqCst)
+    }
+
+    // set before the server first checks for shutdown, so no shutdown goes unseen
+    fn wake_at(&self, listening: Listening) {
+        *self.listening.lock().unwrap() = Some(listening);
+    }
+}
+
+// a listener on every interface is reached through loopback
+fn reachable(addr: &SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => {
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), addr.port())
+        }
+        IpAddr::V6(ip) if ip.is_unspecified() => {
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), addr.port())
+        }
+        _ => *addr,
+    }
+}
+
+// plain streams of connections being served, so they can be closed on shutdown
+#[derive(Clone, Default)]
+struct Connections {
+    streams: Arc<Mutex<HashMap<u64, Stream>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl Connections {
+    fn add(&self, stream: Stream) -> ConnectionGuard {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.streams.lock().unwrap().insert(id, stream);
+        ConnectionGuard {
+            id,
+            connections: self.clone(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.streams.lock().unwrap().len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn shutdown(&self, how: Shutdown) {
+        for stream in self.streams.lock().unwrap().values() {
+            // connection may be closed by client already
+            let _ = stream.shutdown(how);
+        }
+    }
+}
+
+// removes connection from `Connections` when dropped
+struct ConnectionGuard {
+    id: u64,
+    connections: Connections,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        if let Ok(mut streams) = self.connections.streams.lock() {
+            streams.remove(&self.id);
+        }
+    }
+}
+
+fn send_response(writer: &mut BufWriter<Stream>, response: &Response) -> Result<()> {
     let buf = serde_json::to_vec(response)?;
     writer.write_all(&buf[..])?;
     writer.flush()?;
     Ok(())
 }
 
-fn handle_client<T: KvsEngine>(engine: T, stream: TcpStream, logger: &Logger) -> Result<()> {
-    let reader = BufReader::new(&stream);
-    let mut writer = BufWriter::new(&stream);
-    let requests = Deserializer::from_reader(reader).into_iter::<Request>();
+fn handle_client<T: KvsEngine>(
+    mut session: Session<T>,
+    stream: Stream,
+    max_request_size: usize,
+    logger: &Logger,
+) -> Result<()> {
+    let mut writer = BufWriter::new(stream.try_clone()?);
+    let request_size = Rc::new(Cell::new(0));
+    let reader = RequestReader {
+        reader: BufReader::new(stream),
+        read: Rc::clone(&request_size),
+        limit: max_request_size,
+    };
+    // parse into plain json first, so unknown requests can be told apart from broken streams
+    let requests = Deserializer::from_reader(reader).into_iter::<Value>();
 
     for request in requests {
-        if let Ok(request) = request {
-            info!(logger,"request:"; "request" => format!("{:?}", request));
-
-            let response = match request {
-                Request::Get { key } => match engine.get(key) {
-                    Ok(v) => Response::Get(Ok(v)),
-                    Err(e) => Response::Get(Err(e.to_string())),
-                },
-                Request::Remove { key } => match engine.remove(key) {
-                    Ok(_) => Response::Remove(Ok(())),
-                    Err(e) => Response::Remove(Err(e.as_string())),
-                },
-                Request::Set { key, value } => match engine.set(key, value) {
-                    Ok(()) => Response::Set(Ok(())),
-                    Err(e) => Response::Set(Err(e.to_string())),
-                },
-            };
+        let request = match request {
+            Ok(request) => request,
+            Err(_) if request_size.get() > max_request_size => {
+                error!(logger, "request too large"; "limit" => max_request_size);
+                let response = Response::Error(ServerError::InvalidRequest(format!(
+                    "request larger than {} bytes",
+                    max_request_size
+                )));
+                send_response(&mut writer, &response)?;
+                break;
+            }
+            Err(e) if e.is_io() => {
+                // idle timeout, or client went away
+                info!(logger, "closing connection"; "reason" => format!("{}", e));
+                break;
+            }
+            Err(e) => {
+                // the stream can not be recovered from invalid json
+                error!(logger, "can not parse the request"; "error" => format!("{}", e));
+                let response = Response::Error(ServerError::InvalidRequest(format!(
+                    "malformed request: {}",
+                    e
+                )));
+                send_response(&mut writer, &response)?;
+                break;
+            }
+        };
 
-            send_response(&mut writer, &response)?;
+        request_size.set(0);
 
-            info!(
-                logger,
-                "Response sent";
-                "response" => format!("{:?}",response)
-            );
-            // write response
-        } else {
-            error!(logger, "can not parse the request");
+        let response = match serde_json::from_value::<Request>(request) {
+            Ok(request) => {
+                info!(logger,"request:"; "request" => format!("{:?}", request));
+                session.handle(request)
+            }
+            Err(e) => {
+                error!(logger, "unknown request"; "error" => format!("{}", e));
+                Response::Error(ServerError::InvalidRequest(format!(
+                    "unknown request: {}",
+                    e
+                )))
+            }
+        };
+
+        send_response(&mut writer, &response)?;
+
+        info!(
+            logger,
+            "Response sent";
+            "response" => format!("{:?}",response)
+        );
+    }
+    writer.get_ref().close()
+}
+
+// counts bytes read for current request, failing once there are more than `limit`
+struct RequestReader<R> {
+    reader: R,
+    read: Rc<Cell<usize>>,
+    limit: usize,
+}
+
+impl<R: Read> Read for RequestReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = self.reader.read(buf)?;
+        self.read.set(self.read.get().saturating_add(size));
+        if self.read.get() > self.limit {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "request too large",
+            ));
         }
+        Ok(size)
     }
-    Ok(())
+}
+
+// state of one client connection
+struct Session<T: KvsEngine> {
+    engine: T,
+    users: Option<Arc<Users>>,
+    // authenticated user
+    user: Option<User>,
+    stats: StatsSource,
+    leader: Option<SocketAddr>,
+    raft: Option<RaftNode<T>>,
+    backup_dir: Option<Arc<PathBuf>>,
+}
+
+impl<T: KvsEngine> Session<T> {
+    fn new(
+        engine: T,
+        users: Option<Arc<Users>>,
+        stats: StatsSource,
+        leader: Option<SocketAddr>,
+        raft: Option<RaftNode<T>>,
+        backup_dir: Option<Arc<PathBuf>>,
+    ) -> Self {
+        Session {
+            engine,
+            users,
+            user: None,
+            stats,
+            leader,
+            raft,
+            backup_dir,
+        }
+    }
+
+    fn handle(&mut self, request: Request) -> Response {
+        if let Err(e) = self.authorize(&request) {
+            return Response::Error(e);
+        }
+        if let (Some(leader), true) = (self.leader, request.is_write()) {
+            return Response::Error(ServerError::ReadOnly(format!(
+                "server is a replica, send writes to {}",
+                leader
+            )));
+        }
+
+        if let (Some(raft), true) = (&self.raft, request.is_read() || request.is_write()) {
+            return through_raft(raft, &self.engine, request);
+        }
+
+        let engine = &self.engine;
+        match request {
+            Request::Handshake { version, features } => {
+                Response::Handshake(negotiate(version, features))
+            }
+            Request::Auth { user, password } => Response::Auth(self.authenticate(&user, &password)),
+            Request::Stats {} => Response::Stats(Ok((self.stats)())),
+            Request::Get { key } => Response::get(engine.get(key).map_err(ServerError::from)),
+            Request::Remove { key } => {
+                Response::remove(engine.remove(key).map(|_| ()).map_err(ServerError::from))
+            }
+            Request::Set { key, value } => {
+                Response::set(engine.set(key, value).map_err(ServerError::from))
+            }
+            Request::GetMany { keys } => {
+                Response::GetMany(engine.get_many(keys).map_err(ServerError::from))
+            }
+            Request::SetMany { pairs } => {
+                Response::SetMany(engine.set_many(pairs).map_err(ServerError::from))
+            }
+            Request::RemoveMany { keys } => {
+                Response::RemoveMany(engine.remove_many(keys).map_err(ServerError::from))
+            }
+            Request::Scan { after, limit } => Response::Scan(
+                engine
+                    .scan(after, limit.min(SCAN_LIMIT))
+                    .map_err(ServerError::from),
+            ),
+            Request::Backup { dest } => Response::Backup(
+                backup_path(self.backup_dir.as_deref(), &dest)
+                    .and_then(|dest| engine.backup(&dest).map_err(ServerError::from)),
+            ),
+            Request::Replicate { seq } => Response::Replicate(replicate(engine, seq)),
+            Request::Raft { message } => Response::Raft(
+                self.raft()
+                    .and_then(|raft| raft.handle(message))
+                    .map_err(ServerError::from),
+            ),
+            Request::AddMember { addr } => Response::Members(
+                self.raft()
+                    .and_then(|raft| raft.add_member(addr))
+                    .map_err(ServerError::from),
+            ),
+            Request::RemoveMember { addr } => Response::Members(
+                self.raft()
+                    .and_then(|raft| raft.remove_member(addr))
+                    .map_err(ServerError::from),
+            ),
+        }
+    }
+
+    fn raft(&self) -> Result<&RaftNode<T>> {
+        self.raft
+            .as_ref()
+            .ok_or_else(|| Error::invalid_command("server is not in a raft cluster".to_string()))
+    }
+
+    fn authenticate(&mut self, user: &str, password: &str) -> std::result::Result<(), ServerError> {
+        match &self.users {
+            Some(users) => {
+                let user = users.authenticate(user, password)?;
+                self.user = Some(user.clone());
+                Ok(())
+            }
+            // every one is welcome when no users are configured
+            None => Ok(()),
+        }
+    }
+
+    // check whether current user may run the request
+    fn authorize(&self, request: &Request) -> std::result::Result<(), ServerError> {
+        if self.users.is_none() {
+            return Ok(());
+        }
+
+        let (keys, write): (Vec<&str>, bool) = match request {
+            Request::Handshake { .. } | Request::Auth { .. } => return Ok(()),
+            // any user logged in may look
+            Request::Stats {} => (vec![], false),
+            Request::Get { key } => (vec![key], false),
+            Request::GetMany { keys } => (keys.iter().map(String::as_str).collect(), false),
+            Request::Set { key, .. } | Request::Remove { key } => (vec![key], true),
+            Request::SetMany { pairs } => {
+                (pairs.iter().map(|(key, _)| key.as_str()).collect(), true)
+            }
+            Request::RemoveMany { keys } => (keys.iter().map(String::as_str).collect(), true),
+            // followers and scans get every key, so they need a rule covering all of them
+            Request::Replicate { .. } | Request::Scan { .. } => (vec![""], false),
+            // other nodes, admins changing members and backups write every key
+            Request::Raft { .. }
+            | Request::AddMember { .. }
+            | Request::RemoveMember { .. }
+            | Request::Backup { .. } => (vec![""], true),
+        };
+
+        let user = self.user.as_ref().ok_or_else(|| {
+            ServerError::Unauthenticated("authenticate before sending requests".to_string())
+        })?;
+        match keys.into_iter().find(|key| !user.allows(key, write)) {
+            Some(key) => Err(ServerError::PermissionDenied(format!(
+                "user {} may not {} key {}",
+                user.name,
+                if write { "write" } else { "read" },
+                key
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+// where a backup to `dest` goes, which must stay under backup directory `root`
+fn backup_path(root: Option<&PathBuf>, dest: &str) -> std::result::Result<PathBuf, ServerError> {
+    let root = root.ok_or_else(|| {
+        ServerError::PermissionDenied("server takes no backups without a backup dir".to_string())
+    })?;
+    let dest = Path::new(dest);
+    let below = dest
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    if dest.as_os_str().is_empty() || !below {
+        return Err(ServerError::InvalidRequest(format!(
+            "backup destination {} should be a relative path without ..",
+            dest.display()
+        )));
+    }
+    Ok(root.join(dest))
+}
+
+// next writes for a follower at `seq`, or a snapshot when it fell too far behind
+fn replicate<T: KvsEngine>(engine: &T, seq: u64) -> std::result::Result<Replication, ServerError> {
+    match engine.changes_since(seq, REPLICATION_BATCH)? {
+        Some(changes) => Ok(Replication::Changes(changes)),
+        None => {
+            let (seq, pairs) = engine.snapshot()?;
+            Ok(Replication::Snapshot { seq, pairs })
+        }
+    }
+}
+
+// Reads are served by a leader most members still follow, writes are applied
+// once most members logged them
+fn through_raft<T: KvsEngine>(raft: &RaftNode<T>, engine: &T, request: Request) -> Response {
+    let set = |key, value| Command::Set { key, value, seq: 0 };
+    let remove = |key| Command::Remove { key, seq: 0 };
+    let result = if request.is_read() {
+        raft.confirm_leader()
+    } else {
+        raft.check_leader()
+    };
+    match request {
+        Request::Get { key } => Response::get(
+            result
+                .and_then(|()| engine.get(key))
+                .map_err(ServerError::from),
+        ),
+        Request::GetMany { keys } => Response::GetMany(
+            result
+                .and_then(|()| engine.get_many(keys))
+                .map_err(ServerError::from),
+        ),
+        Request::Scan { after, limit } => Response::Scan(
+            result
+                .and_then(|()| engine.scan(after, limit.min(SCAN_LIMIT)))
+                .map_err(ServerError::from),
+        ),
+        Request::Set { key, value } => Response::set(
+            result
+                .and_then(|()| raft.propose(vec![set(key, value)]))
+                .map_err(ServerError::from),
+        ),
+        Request::SetMany { pairs } => Response::SetMany(
+            result
+                .and_then(|()| raft.propose(pairs.into_iter().map(|(k, v)| set(k, v)).collect()))
+                .map_err(ServerError::from),
+        ),
+        Request::Remove { key } => Response::remove(
+            result
+                .and_then(|()| match engine.get(key.clone())? {
+                    Some(_) => raft.propose(vec![remove(key)]),
+                    None => Err(Error::key_not_found(format!("key {} not found", key))),
+                })
+                .map_err(ServerError::from),
+        ),
+        Request::RemoveMany { keys } => Response::RemoveMany(
+            result
+                .and_then(|()| {
+                    let found = engine.get_many(keys.clone())?;
+                    let removed: Vec<bool> = found.iter().map(Option::is_some).collect();
+                    let commands = keys
+                        .into_iter()
+                        .zip(&removed)
+                        .filter(|(_, removed)| **removed)
+                        .map(|(key, _)| remove(key))
+                        .collect();
+                    raft.propose(commands)?;
+                    Ok(removed)
+                })
+                .map_err(ServerError::from),
+        ),
+        request => Response::Error(ServerError::InvalidRequest(format!(
+            "{:?} does not touch stored data",
+            request
+        ))),
+    }
+}
+
+// agree on a protocol version and the features both sides know about
+fn negotiate(version: u32, features: Vec<String>) -> std::result::Result<Handshake, ServerError> {
+    if version < MIN_PROTOCOL_VERSION {
+        return Err(ServerError::UnsupportedVersion(format!(
+            "protocol version {} is not supported, server speaks {} to {}",
+            version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+        )));
+    }
+
+    let features = features
+        .into_iter()
+        .filter(|feature| FEATURES.contains(&feature.as_str()))
+        .collect();
+
+    Ok(Handshake {
+        version: version.min(PROTOCOL_VERSION),
+        features,
+    })
 }
@@ -0,0 +1,280 @@
+use crate::client::Client;
+use crate::error::{Error, ErrorKind, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+
+/// Points each node gets on the ring by default
+pub const DEFAULT_VNODES: usize = 160;
+// pairs read or moved per request while migrating
+const MIGRATION_BATCH: usize = 1024;
+
+// keys of each node, with their positions in the request
+type Groups = HashMap<SocketAddr, (Vec<usize>, Vec<String>)>;
+
+/// 64 bit FNV-1a, stable across builds and platforms unlike `DefaultHasher`
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in bytes {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+// place on the ring, fnv1a alone leaves keys differing in last bytes close together
+fn position(bytes: &[u8]) -> u64 {
+    // finalizer of MurmurHash3, spreading every bit over the whole range
+    let mut hash = fnv1a(bytes);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+/// Consistent hash ring.
+/// Each node owns the keys hashing up to each of its points,
+/// so adding or removing a node only moves keys next to its points.
+#[derive(Debug, Clone)]
+pub struct Ring {
+    points: BTreeMap<u64, SocketAddr>,
+    vnodes: usize,
+}
+
+impl Ring {
+    pub fn new(nodes: &[SocketAddr], vnodes: usize) -> Self {
+        let mut ring = Ring {
+            points: BTreeMap::new(),
+            vnodes,
+        };
+        for node in nodes {
+            ring.add(*node);
+        }
+        ring
+    }
+
+    pub fn add(&mut self, node: SocketAddr) {
+        for i in 0..self.vnodes {
+            self.points.insert(point(node, i), node);
+        }
+    }
+
+    pub fn remove(&mut self, node: SocketAddr) {
+        self.points.retain(|_, owner| *owner != node);
+    }
+
+    /// node owning `key`, `None` for an empty ring
+    pub fn node_for(&self, key: &str) -> Option<SocketAddr> {
+        self.owner(position(key.as_bytes()))
+    }
+
+    /// nodes owning the ranges `node` would take if it was added, each once
+    pub fn donors(&self, node: SocketAddr) -> Vec<SocketAddr> {
+        let mut donors: Vec<SocketAddr> = (0..self.vnodes)
+            .filter_map(|i| self.owner(point(node, i)))
+            .filter(|donor| *donor != node)
+            .collect();
+        donors.sort_unstable();
+        donors.dedup();
+        donors
+    }
+
+    // node owning the next point from `hash` on
+    fn owner(&self, hash: u64) -> Option<SocketAddr> {
+        self.points
+            .range(hash..)
+            .next()
+            .or_else(|| self.points.iter().next())
+            .map(|(_, node)| *node)
+    }
+
+    pub fn nodes(&self) -> Vec<SocketAddr> {
+        let mut nodes: Vec<SocketAddr> = self.points.values().copied().collect();
+        nodes.sort_unstable();
+        nodes.dedup();
+        nodes
+    }
+}
+
+fn point(node: SocketAddr, vnode: usize) -> u64 {
+    position(format!("{}#{}", node, vnode).as_bytes())
+}
+
+/// Spreads keys over many servers, each holding the keys the ring gives it
+pub struct ShardedClient<'a> {
+    ring: Ring,
+    clients: HashMap<SocketAddr, Client<'a>>,
+    auth: Option<(String, String)>,
+}
+
+impl<'a> ShardedClient<'a> {
+    /// client of `nodes`, connecting to each on first request it gets
+    pub fn new(nodes: &[SocketAddr]) -> Self {
+        ShardedClient {
+            ring: Ring::new(nodes, DEFAULT_VNODES),
+            clients: HashMap::new(),
+            auth: None,
+        }
+    }
+
+    /// place nodes on the ring `vnodes` times each.
+    /// Every client of the same servers must use the same number.
+    pub fn with_vnodes(mut self, vnodes: usize) -> Self {
+        self.ring = Ring::new(&self.ring.nodes(), vnodes);
+        self
+    }
+
+    /// log in as `user` on every node
+    pub fn with_auth(mut self, user: String, password: String) -> Self {
+        self.auth = Some((user, password));
+        self
+    }
+
+    pub fn ring(&self) -> &Ring {
+        &self.ring
+    }
+
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        let node = self.node_for(&key)?;
+        self.call(node, |client| client.get(key))
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        let node = self.node_for(&key)?;
+        self.call(node, |client| client.set(key, value))
+    }
+
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        let node = self.node_for(&key)?;
+        self.call(node, |client| client.remove(key))
+    }
+
+    /// get values of many keys, with one request per node
+    pub fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let mut values = vec![None; keys.len()];
+        for (node, (positions, keys)) in self.group(keys)? {
+            let found = self.call(node, |client| client.get_many(keys))?;
+            for (position, value) in positions.into_iter().zip(found) {
+                values[position] = value;
+            }
+        }
+        Ok(values)
+    }
+
+    pub fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        let mut by_node: HashMap<SocketAddr, Vec<(String, String)>> = HashMap::new();
+        for (key, value) in pairs {
+            let node = self.node_for(&key)?;
+            by_node.entry(node).or_default().push((key, value));
+        }
+        for (node, pairs) in by_node {
+            self.call(node, |client| client.set_many(pairs))?;
+        }
+        Ok(())
+    }
+
+    /// remove many keys, telling which of them existed
+    pub fn remove_many(&mut self, keys: Vec<String>) -> Result<Vec<bool>> {
+        let mut removed = vec![false; keys.len()];
+        for (node, (positions, keys)) in self.group(keys)? {
+            let existed = self.call(node, |client| client.remove_many(keys))?;
+            for (position, existed) in positions.into_iter().zip(existed) {
+                removed[position] = existed;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Put `node` on the ring and move the keys it now owns from other nodes.
+    /// Only nodes owning ranges `node` takes are read, but stores keep keys in key
+    /// order, not hash order, so each of them is scanned in full.
+    /// Returns how many keys moved. Writes made by other clients while
+    /// migrating may be lost, so stop them first.
+    pub fn add_node(&mut self, node: SocketAddr) -> Result<usize> {
+        let donors = self.ring.donors(node);
+        self.ring.add(node);
+        let mut moved = 0;
+        for from in donors {
+            moved += self.migrate(from)?;
+        }
+        Ok(moved)
+    }
+
+    /// Move keys of `node` to the nodes owning them without it, then take it off the ring.
+    /// Returns how many keys moved.
+    pub fn remove_node(&mut self, node: SocketAddr) -> Result<usize> {
+        if self.ring.nodes() == [node] {
+            return Err(Error::invalid_command(
+                "can not remove the last node".to_string(),
+            ));
+        }
+        self.ring.remove(node);
+        let moved = self.migrate(node)?;
+        self.clients.remove(&node);
+        Ok(moved)
+    }
+
+    // move keys stored on `from` which the ring gives to other nodes
+    fn migrate(&mut self, from: SocketAddr) -> Result<usize> {
+        let mut moved = 0;
+        let mut after = None;
+        loop {
+            let page = self.call(from, |client| client.scan(after.clone(), MIGRATION_BATCH))?;
+            let last = match page.last() {
+                Some((key, _)) => key.clone(),
+                None => return Ok(moved),
+            };
+            let leaving: Vec<(String, String)> = page
+                .into_iter()
+                .filter(|(key, _)| self.ring.node_for(key) != Some(from))
+                .collect();
+            // copy before removing, so a key is always on one of the nodes
+            let keys = leaving.iter().map(|(key, _)| key.clone()).collect();
+            moved += leaving.len();
+            self.set_many(leaving)?;
+            self.call(from, |client| client.remove_many(keys))?;
+            after = Some(last);
+        }
+    }
+
+    fn group(&self, keys: Vec<String>) -> Result<Groups> {
+        let mut by_node = Groups::new();
+        for (position, key) in keys.into_iter().enumerate() {
+            let group = by_node.entry(self.node_for(&key)?).or_default();
+            group.0.push(position);
+            group.1.push(key);
+        }
+        Ok(by_node)
+    }
+
+    fn node_for(&self, key: &str) -> Result<SocketAddr> {
+        self.ring
+            .node_for(key)
+            .ok_or_else(|| Error::from(ErrorKind::Error("no nodes to store keys on".to_string())))
+    }
+
+    // run `request` on `node`, reconnecting next time if connection broke
+    fn call<R, F>(&mut self, node: SocketAddr, request: F) -> Result<R>
+    where
+        F: FnOnce(&mut Client<'a>) -> Result<R>,
+    {
+        let result = request(self.client(node)?);
+        if let Err(e) = &result {
+            if let ErrorKind::IO(_) = e.kind() {
+                self.clients.remove(&node);
+            }
+        }
+        result
+    }
+
+    fn client(&mut self, node: SocketAddr) -> Result<&mut Client<'a>> {
+        if !self.clients.contains_key(&node) {
+            let mut client = Client::connect(node)?;
+            if let Some((user, password)) = &self.auth {
+                client.auth(user.to_owned(), password.to_owned())?;
+            }
+            self.clients.insert(node, client);
+        }
+        Ok(self.clients.get_mut(&node).unwrap())
+    }
+}
@@ -0,0 +1,216 @@
+use crate::error::{Error, ErrorKind, Result};
+use rustls::pki_types::ServerName;
+use rustls::{
+    ClientConfig, ClientConnection, ConnectionCommon, ServerConfig, ServerConnection, StreamOwned,
+};
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::ops::DerefMut;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Connection between client and server, plain or encrypted
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    // tls session can not be split, so both halves share it
+    Tls(Arc<Mutex<TlsStream>>),
+}
+
+pub struct TlsStream {
+    session: TlsSession,
+    // after an error the session must not be driven any more,
+    // or it may wait forever for a peer which already gave up
+    broken: bool,
+}
+
+enum TlsSession {
+    Server(StreamOwned<ServerConnection, TcpStream>),
+    Client(StreamOwned<ClientConnection, TcpStream>),
+}
+
+impl TlsStream {
+    fn new(session: TlsSession) -> Self {
+        TlsStream {
+            session,
+            broken: false,
+        }
+    }
+
+    fn guard<T>(&mut self, op: impl FnOnce(&mut TlsSession) -> io::Result<T>) -> io::Result<T> {
+        if self.broken {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "tls session is broken",
+            ));
+        }
+        let result = op(&mut self.session);
+        self.broken = result.is_err();
+        result
+    }
+}
+
+impl Stream {
+    /// accept a tls session on server side, handshake happens on first read
+    pub fn tls_server(stream: TcpStream, config: Arc<ServerConfig>) -> Result<Stream> {
+        let conn = ServerConnection::new(config)?;
+        let stream = TlsStream::new(TlsSession::Server(StreamOwned::new(conn, stream)));
+        Ok(Stream::Tls(Arc::new(Mutex::new(stream))))
+    }
+
+    /// open a tls session to server named `server_name`
+    pub fn tls_client(
+        stream: TcpStream,
+        server_name: &str,
+        config: Arc<ClientConfig>,
+    ) -> Result<Stream> {
+        let name = ServerName::try_from(server_name.to_owned()).map_err(|_| {
+            Error::from(ErrorKind::Tls(format!(
+                "invalid server name {}",
+                server_name
+            )))
+        })?;
+        let conn = ClientConnection::new(config, name)?;
+        let stream = TlsStream::new(TlsSession::Client(StreamOwned::new(conn, stream)));
+        Ok(Stream::Tls(Arc::new(Mutex::new(stream))))
+    }
+
+    pub fn try_clone(&self) -> Result<Stream> {
+        match self {
+            Stream::Tcp(stream) => Ok(Stream::Tcp(stream.try_clone()?)),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Ok(Stream::Unix(stream.try_clone()?)),
+            Stream::Tls(stream) => Ok(Stream::Tls(Arc::clone(stream))),
+        }
+    }
+
+    /// address of the other end, for logging
+    pub fn peer(&self) -> String {
+        match self {
+            Stream::Tcp(stream) => stream
+                .peer_addr()
+                .map(|addr| addr.to_string())
+                .unwrap_or_default(),
+            #[cfg(unix)]
+            Stream::Unix(_) => "unix".to_string(),
+            Stream::Tls(_) => "tls".to_string(),
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_nonblocking(nonblocking)?,
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_nonblocking(nonblocking)?,
+            Stream::Tls(_) => {}
+        }
+        Ok(())
+    }
+
+    /// fail reads which wait longer than `timeout`, `None` waits forever
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout)?,
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_read_timeout(timeout)?,
+            Stream::Tls(_) => {}
+        }
+        Ok(())
+    }
+
+    /// shut down the socket under stream, waking up blocked reads.
+    /// Does nothing for tls, pass the plain stream it was built on instead.
+    pub fn shutdown(&self, how: Shutdown) -> Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.shutdown(how)?,
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.shutdown(how)?,
+            Stream::Tls(_) => {}
+        }
+        Ok(())
+    }
+
+    /// tell peer no more data will be sent
+    pub fn close(&self) -> Result<()> {
+        if let Stream::Tls(stream) = self {
+            let mut stream = stream.lock().expect("unable to lock tls stream");
+            stream.guard(|session| match session {
+                TlsSession::Server(s) => close_notify(&mut s.conn, &mut s.sock),
+                TlsSession::Client(s) => close_notify(&mut s.conn, &mut s.sock),
+            })?;
+        }
+        Ok(())
+    }
+}
+
+// only write pending records, peer may be gone already so never wait for reads
+fn close_notify<C, S>(conn: &mut C, sock: &mut TcpStream) -> io::Result<()>
+where
+    C: DerefMut<Target = ConnectionCommon<S>>,
+{
+    conn.send_close_notify();
+    while conn.wants_write() {
+        conn.write_tls(sock)?;
+    }
+    Ok(())
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.read(buf),
+            Stream::Tls(stream) => stream.lock().expect("unable to lock tls stream").read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write(buf),
+            Stream::Tls(stream) => stream.lock().expect("unable to lock tls stream").write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.flush(),
+            Stream::Tls(stream) => stream.lock().expect("unable to lock tls stream").flush(),
+        }
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.guard(|session| match session {
+            TlsSession::Server(stream) => stream.read(buf),
+            TlsSession::Client(stream) => stream.read(buf),
+        })
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.guard(|session| match session {
+            TlsSession::Server(stream) => stream.write(buf),
+            TlsSession::Client(stream) => stream.write(buf),
+        })
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.guard(|session| match session {
+            TlsSession::Server(stream) => stream.flush(),
+            TlsSession::Client(stream) => stream.flush(),
+        })
+    }
+}
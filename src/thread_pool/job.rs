@@ -0,0 +1,192 @@
+use super::{panic_message, ThreadPool};
+use crate::error::Result;
+use crossbeam::sync::WaitGroup;
+use std::any::Any;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+
+/// Why a job gave no result
+#[derive(Debug)]
+pub enum JobError {
+    /// job panicked, with what it passed to `panic!`
+    Panicked(Box<dyn Any + Send>),
+    /// job was thrown away before it ran, when pool was shut down or its deadline passed
+    Cancelled,
+}
+
+impl JobError {
+    /// message passed to `panic!`, if any
+    pub fn message(&self) -> Option<&str> {
+        match self {
+            JobError::Panicked(payload) => panic_message(payload.as_ref()),
+            JobError::Cancelled => None,
+        }
+    }
+}
+
+/// Result of a job started by `ThreadPool::spawn`.
+/// Wait for it with `join`, or `.await` it.
+pub struct JobHandle<T> {
+    slot: Arc<Slot<T>>,
+}
+
+struct Slot<T> {
+    state: Mutex<SlotState<T>>,
+    done: Condvar,
+}
+
+struct SlotState<T> {
+    result: Option<std::result::Result<T, JobError>>,
+    waker: Option<Waker>,
+}
+
+impl<T> JobHandle<T> {
+    /// wait until job is done and take its result
+    pub fn join(self) -> std::result::Result<T, JobError> {
+        let mut state = self.slot.state.lock().unwrap();
+        loop {
+            if let Some(result) = state.result.take() {
+                return result;
+            }
+            state = self.slot.done.wait(state).unwrap();
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.slot.state.lock().unwrap().result.is_some()
+    }
+}
+
+impl<T> Future for JobHandle<T> {
+    type Output = std::result::Result<T, JobError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.slot.state.lock().unwrap();
+        match state.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+// job side of a `JobHandle`, cancels it when dropped without result
+pub(super) struct Completer<T> {
+    slot: Option<Arc<Slot<T>>>,
+}
+
+pub(super) fn handle<T>() -> (Completer<T>, JobHandle<T>) {
+    let slot = Arc::new(Slot {
+        state: Mutex::new(SlotState {
+            result: None,
+            waker: None,
+        }),
+        done: Condvar::new(),
+    });
+    let completer = Completer {
+        slot: Some(Arc::clone(&slot)),
+    };
+    (completer, JobHandle { slot })
+}
+
+impl<T> Completer<T> {
+    pub(super) fn run<F: FnOnce() -> T>(mut self, job: F) {
+        match panic::catch_unwind(AssertUnwindSafe(job)) {
+            Ok(value) => self.complete(Ok(value)),
+            Err(payload) => {
+                // pool still sees the panic, handle keeps what was passed to `panic!`
+                let message = panic_message(payload.as_ref())
+                    .unwrap_or("job panicked")
+                    .to_owned();
+                self.complete(Err(JobError::Panicked(payload)));
+                panic::resume_unwind(Box::new(message));
+            }
+        }
+    }
+
+    fn complete(&mut self, result: std::result::Result<T, JobError>) {
+        if let Some(slot) = self.slot.take() {
+            let mut state = slot.state.lock().unwrap();
+            state.result = Some(result);
+            slot.done.notify_all();
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+impl<T> Drop for Completer<T> {
+    fn drop(&mut self) {
+        self.complete(Err(JobError::Cancelled));
+    }
+}
+
+/// Jobs spawned here may borrow anything living longer than the scope,
+/// see `scope`
+pub struct Scope<'env, P: ThreadPool> {
+    pool: &'env P,
+    // one clone per job not finished or dropped yet
+    pending: WaitGroup,
+    // 'env must not shrink, or jobs could borrow data dropped before scope ends
+    env: PhantomData<&'env mut &'env ()>,
+}
+
+// job borrowing scope data, fields drop in order so `pending` is released last
+struct ScopedJob<F> {
+    job: Option<F>,
+    _pending: WaitGroup,
+}
+
+impl<'env, P: ThreadPool> Scope<'env, P> {
+    /// like `ThreadPool::spawn`, `job` only has to outlive the scope.
+    /// A panic is reported through the handle.
+    pub fn spawn<F, T>(&self, job: F) -> Result<JobHandle<T>>
+    where
+        F: FnOnce() -> T + Send + 'env,
+        T: Send + 'env,
+    {
+        let (completer, handle) = handle();
+        let mut scoped = ScopedJob {
+            job: Some(move || completer.run(job)),
+            _pending: self.pending.clone(),
+        };
+        let job: Box<dyn FnOnce() + Send + 'env> = Box::new(move || {
+            if let Some(job) = scoped.job.take() {
+                job();
+            }
+        });
+        // SAFETY: `scope` waits for every job to run or be dropped before
+        // returning, so nothing borrowed from 'env is used after it ends
+        let job: Box<dyn FnOnce() + Send + 'static> = unsafe { std::mem::transmute(job) };
+        self.pool.execute(job)?;
+        Ok(handle)
+    }
+}
+
+/// Run `f` with a scope to spawn jobs borrowing local data on `pool`.
+/// Returns once every job spawned is done.
+pub fn scope<'env, P, F, R>(pool: &'env P, f: F) -> R
+where
+    P: ThreadPool,
+    F: FnOnce(&Scope<'env, P>) -> R,
+{
+    let scope = Scope {
+        pool,
+        pending: WaitGroup::new(),
+        env: PhantomData,
+    };
+    // jobs must be waited for even if `f` panics
+    let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+    scope.pending.wait();
+    match result {
+        Ok(result) => result,
+        Err(payload) => panic::resume_unwind(payload),
+    }
+}
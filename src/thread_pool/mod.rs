@@ -1,8 +1,23 @@
 use crate::error::Result;
+use std::any::Any;
+use std::sync::Arc;
+use std::time::Instant;
 
+mod job;
+mod naive;
 mod pool;
+mod queue;
+mod rayon;
+mod stats;
+mod stealing;
 pub mod supervisor;
-pub use pool::QueueThreadPool;
+pub use self::rayon::RayonThreadPool;
+pub use job::{scope, JobError, JobHandle, Scope};
+pub use naive::NaiveThreadPool;
+pub use pool::{QueueThreadPool, ShutdownMode};
+pub use queue::Priority;
+pub use stats::PoolStats;
+pub use stealing::StealingThreadPool;
 
 pub trait ThreadPool {
     fn new(size: usize) -> Result<Self>
@@ -13,12 +28,71 @@ pub trait ThreadPool {
     where
         // since function works in a thread, it must have static lifetime
         F: Send + FnOnce() + 'static;
+
+    /// like `execute`, but fails with `ErrorKind::Overloaded`
+    /// instead of waiting when pool can not take more jobs
+    fn try_execute<F>(&self, job: F) -> Result<()>
+    where
+        F: Send + FnOnce() + 'static,
+    {
+        self.execute(job)
+    }
+
+    /// like `execute`, handing back the job's result or panic
+    fn spawn<F, T>(&self, job: F) -> Result<JobHandle<T>>
+    where
+        F: Send + FnOnce() -> T + 'static,
+        T: Send + 'static,
+    {
+        let (completer, handle) = job::handle();
+        self.execute(move || completer.run(job))?;
+        Ok(handle)
+    }
+
+    fn stats(&self) -> PoolStats;
 }
 
 pub type Job = Box<dyn Send + FnOnce() + 'static>;
 
+/// Called with details of every job that panics
+pub type PanicHandler = Arc<dyn Fn(&JobPanic) + Send + Sync>;
+
+/// A job and what is known about it
+pub struct Task {
+    pub id: u64,
+    pub name: Option<String>,
+    /// thrown away if not started by then
+    pub deadline: Option<Instant>,
+    pub job: Job,
+}
+
+/// A job panicked, and where
+pub struct JobPanic<'a> {
+    /// jobs are numbered in the order they were submitted
+    pub job: u64,
+    pub name: Option<&'a str>,
+    pub worker: usize,
+    pub payload: &'a (dyn Any + Send),
+}
+
+impl JobPanic<'_> {
+    /// message passed to `panic!`, if any
+    pub fn message(&self) -> Option<&str> {
+        panic_message(self.payload)
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> Option<&str> {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+}
+
 pub enum Message {
     Dead(usize),
-    Work(Job),
+    Work(Task),
+    // ask one worker above the minimum to exit
+    Retire,
     Terminate,
 }
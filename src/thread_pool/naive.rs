@@ -0,0 +1,43 @@
+use super::{stats::Counters, PoolStats, ThreadPool};
+use crate::error::Result;
+use std::sync::Arc;
+use std::thread;
+
+/// Spawns a new thread for every job
+pub struct NaiveThreadPool {
+    counters: Arc<Counters>,
+}
+
+impl ThreadPool for NaiveThreadPool {
+    fn new(_size: usize) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        Ok(NaiveThreadPool {
+            counters: Arc::new(Counters::default()),
+        })
+    }
+
+    fn execute<F>(&self, job: F) -> Result<()>
+    where
+        F: Send + FnOnce() + 'static,
+    {
+        let counters = Arc::clone(&self.counters);
+        counters.queue();
+        thread::spawn(move || {
+            if let Err(payload) = counters.run(Box::new(job)) {
+                std::panic::resume_unwind(payload);
+            }
+        });
+        Ok(())
+    }
+
+    /// every busy thread is a worker
+    fn stats(&self) -> PoolStats {
+        let stats = self.counters.stats(0);
+        PoolStats {
+            workers: stats.busy,
+            ..stats
+        }
+    }
+}
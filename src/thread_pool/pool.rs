@@ -1,11 +1,154 @@
-use super::{supervisor::Supervisor, Message, ThreadPool};
-use crate::error::Error;
-use crossbeam::channel::{unbounded, Receiver, Sender};
+use super::{
+    job,
+    queue::{self, Priority, QueueReceiver, QueueSender},
+    stats::Counters,
+    supervisor::Supervisor,
+    JobHandle, JobPanic, Message, PanicHandler, PoolStats, Task, ThreadPool,
+};
+use crate::error::{Error, ErrorKind};
+use crossbeam::channel::{unbounded, RecvTimeoutError, SendError, Sender, TrySendError};
+use std::panic;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// how often shutdown checks whether workers have exited
+const JOIN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+// how long workers above the minimum wait for a job before exiting
+const KEEP_ALIVE: Duration = Duration::from_secs(60);
+
 pub struct QueueThreadPool {
-    sender: Sender<Message>,
+    sender: QueueSender,
+    // kept to throw away queued jobs when shutdown cancels them
+    receiver: QueueReceiver,
     supervisor_sender: Sender<Message>,
-    size: usize,
+    spawner: Spawner,
+    state: Arc<PoolState>,
+    next_job: AtomicU64,
+}
+
+// shared by pool, supervisor and workers
+pub struct PoolState {
+    panic_handler: RwLock<Option<PanicHandler>>,
+    // set once pool shuts down, dead workers are not revived after that
+    closed: AtomicBool,
+    counters: Counters,
+    // workers started and not exited yet
+    live: AtomicUsize,
+    // workers kept when idle, and most workers pool grows to when busy
+    min: AtomicUsize,
+    max: AtomicUsize,
+    keep_alive: RwLock<Duration>,
+    next_worker: AtomicUsize,
+}
+
+impl PoolState {
+    fn new(size: usize) -> Self {
+        PoolState {
+            panic_handler: RwLock::new(None),
+            closed: AtomicBool::new(false),
+            counters: Counters::default(),
+            live: AtomicUsize::new(0),
+            min: AtomicUsize::new(size),
+            max: AtomicUsize::new(size),
+            keep_alive: RwLock::new(KEEP_ALIVE),
+            next_worker: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    pub fn counters(&self) -> &Counters {
+        &self.counters
+    }
+
+    // count a worker about to be started
+    pub fn reserve(&self) {
+        self.live.fetch_add(1, Ordering::SeqCst);
+    }
+
+    // like `reserve`, unless there are `limit` workers already
+    fn reserve_below(&self, limit: usize) -> bool {
+        self.live
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |live| {
+                if live < limit {
+                    Some(live + 1)
+                } else {
+                    None
+                }
+            })
+            .is_ok()
+    }
+
+    // let a worker go if there are more than the minimum
+    fn retire(&self) -> bool {
+        let min = self.min.load(Ordering::SeqCst);
+        self.live
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |live| {
+                if live > min {
+                    Some(live - 1)
+                } else {
+                    None
+                }
+            })
+            .is_ok()
+    }
+
+    // how long an idle worker waits before trying to retire, `None` when it may not
+    fn keep_alive(&self) -> Option<Duration> {
+        if self.workers() > self.min.load(Ordering::SeqCst) {
+            Some(*self.keep_alive.read().unwrap())
+        } else {
+            None
+        }
+    }
+
+    fn workers(&self) -> usize {
+        self.live.load(Ordering::SeqCst)
+    }
+}
+
+// starts workers, shared by pool and supervisor
+#[derive(Clone)]
+pub struct Spawner {
+    receiver: QueueReceiver,
+    notifier: Sender<Message>,
+    workers: Arc<Mutex<Vec<Worker>>>,
+    state: Arc<PoolState>,
+}
+
+impl Spawner {
+    // start a worker counted by `PoolState::reserve` already
+    pub fn spawn(&self) {
+        let mut workers = self.workers.lock().unwrap();
+        // checked under lock, so shutdown knows every worker it has to stop
+        if self.state.is_closed() {
+            self.state.live.fetch_sub(1, Ordering::SeqCst);
+            return;
+        }
+        // forget workers which exited
+        workers.retain_mut(Worker::try_join);
+        let id = self.state.next_worker.fetch_add(1, Ordering::SeqCst);
+        let receiver = JobReceiver::new(
+            self.receiver.clone(),
+            self.notifier.clone(),
+            id,
+            Arc::clone(&self.state),
+        );
+        workers.push(Worker::new(id, receiver));
+    }
+}
+
+/// What happens to queued jobs when pool shuts down
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownMode {
+    /// run every queued job first
+    Drain,
+    /// throw queued jobs away, only jobs already running finish
+    Cancel,
 }
 
 impl ThreadPool for QueueThreadPool {
@@ -13,44 +156,271 @@ impl ThreadPool for QueueThreadPool {
     where
         Self: Sized,
     {
+        QueueThreadPool::with_queue(size, None)
+    }
+
+    fn execute<F>(&self, job: F) -> crate::error::Result<()>
+    where
+        // since function works in a thread, it must have static lifetime
+        F: Send + FnOnce() + 'static,
+    {
+        self.send(Priority::Normal, None, None, job)
+    }
+
+    fn try_execute<F>(&self, job: F) -> crate::error::Result<()>
+    where
+        F: Send + FnOnce() + 'static,
+    {
+        self.state.counters.queue();
+        let sent = self
+            .sender
+            .jobs(Priority::Normal)
+            .try_send(self.task(None, None, job));
+        // a full queue means workers are busy too
+        self.grow();
+        match sent {
+            Ok(()) => Ok(()),
+            Err(err) => {
+                self.state.counters.dequeue();
+                match err {
+                    TrySendError::Full(_) => Err(Error::from(ErrorKind::Overloaded(
+                        "too many jobs waiting in thread pool".to_string(),
+                    ))),
+                    TrySendError::Disconnected(msg) => Err(Error::from(SendError(msg))),
+                }
+            }
+        }
+    }
+
+    fn stats(&self) -> PoolStats {
+        self.state.counters.stats(self.state.workers())
+    }
+}
+
+impl QueueThreadPool {
+    /// pool whose queue holds at most `capacity` waiting jobs of each priority,
+    /// `try_execute` fails when it is full
+    pub fn bounded(size: usize, capacity: usize) -> crate::error::Result<Self> {
+        QueueThreadPool::with_queue(size, Some(capacity))
+    }
+
+    fn with_queue(size: usize, capacity: Option<usize>) -> crate::error::Result<Self> {
+        let (worker_sender, worker_receiver) = queue::queue(capacity);
         let (supervisor_sender, supervisor_receiver) = unbounded::<Message>();
-        let (worker_sender, worker_receiver) = unbounded::<Message>();
-        let pool_supervisor_sender = supervisor_sender.clone();
+        let state = Arc::new(PoolState::new(size));
+        let spawner = Spawner {
+            receiver: worker_receiver.clone(),
+            notifier: supervisor_sender.clone(),
+            workers: Arc::new(Mutex::new(Vec::with_capacity(size))),
+            state: Arc::clone(&state),
+        };
 
+        // workers are started here, so shutdown right after creating pool finds them
+        for _ in 0..size {
+            state.reserve();
+            spawner.spawn();
+        }
+        let mut supervisor =
+            Supervisor::new(supervisor_receiver, spawner.clone(), Arc::clone(&state));
         thread::spawn(move || {
-            let mut supervisor = Supervisor::new(
-                supervisor_receiver,
-                supervisor_sender,
-                worker_receiver,
-                size,
-            );
             // supervise all
             supervisor.watch();
         });
 
         Ok(QueueThreadPool {
-            size,
             sender: worker_sender,
-            supervisor_sender: pool_supervisor_sender,
+            receiver: worker_receiver,
+            supervisor_sender,
+            spawner,
+            state,
+            next_job: AtomicU64::new(0),
         })
     }
 
-    fn execute<F>(&self, job: F) -> crate::error::Result<()>
+    /// start more workers, up to `max`, while jobs wait for a busy pool
+    pub fn with_max_workers(self, max: usize) -> Self {
+        let min = self.state.min.load(Ordering::SeqCst);
+        self.state.max.store(max.max(min), Ordering::SeqCst);
+        self
+    }
+
+    /// how long a worker above the minimum waits for a job before it exits
+    pub fn with_keep_alive(self, keep_alive: Duration) -> Self {
+        *self.state.keep_alive.write().unwrap() = keep_alive;
+        self
+    }
+
+    /// Keep `size` workers running, raising the maximum if it is lower.
+    /// Workers above `size` exit once they have no job to run.
+    pub fn resize(&self, size: usize) -> crate::error::Result<()> {
+        if self.state.is_closed() {
+            return Err(Error::from(ErrorKind::ThreadPoolError(
+                "thread pool is shut down".to_owned(),
+            )));
+        }
+        self.state.min.store(size, Ordering::SeqCst);
+        self.state.max.fetch_max(size, Ordering::SeqCst);
+        while self.state.reserve_below(size) {
+            self.spawner.spawn();
+        }
+        for _ in size..self.state.workers() {
+            let _ = self.sender.control().send(Message::Retire);
+        }
+        Ok(())
+    }
+
+    /// call `handler` with details of every job that panics.
+    /// The worker still dies and is replaced afterwards.
+    pub fn with_panic_handler<H>(self, handler: H) -> Self
+    where
+        H: Fn(&JobPanic) + Send + Sync + 'static,
+    {
+        *self.state.panic_handler.write().unwrap() = Some(Arc::new(handler));
+        self
+    }
+
+    /// like `execute`, naming the job in panic reports
+    pub fn execute_named<F>(&self, name: impl Into<String>, job: F) -> crate::error::Result<()>
     where
-        // since function works in a thread, it must have static lifetime
         F: Send + FnOnce() + 'static,
     {
-        match self.sender.send(Message::Work(Box::new(job))) {
-            Ok(()) => Ok(()),
-            Err(err) => Err(Error::from(err)),
+        self.send(Priority::Normal, Some(name.into()), None, job)
+    }
+
+    /// like `execute`, jobs of higher `priority` run first.
+    /// A job still waiting at `deadline` is thrown away.
+    pub fn execute_with<F>(
+        &self,
+        priority: Priority,
+        deadline: Option<Instant>,
+        job: F,
+    ) -> crate::error::Result<()>
+    where
+        F: Send + FnOnce() + 'static,
+    {
+        self.send(priority, None, deadline, job)
+    }
+
+    /// like `spawn`, scheduled as in `execute_with`.
+    /// Jobs thrown away at their deadline end with `JobError::Cancelled`.
+    pub fn spawn_with<F, T>(
+        &self,
+        priority: Priority,
+        deadline: Option<Instant>,
+        job: F,
+    ) -> crate::error::Result<JobHandle<T>>
+    where
+        F: Send + FnOnce() -> T + 'static,
+        T: Send + 'static,
+    {
+        let (completer, handle) = job::handle();
+        self.send(priority, None, deadline, move || completer.run(job))?;
+        Ok(handle)
+    }
+
+    /// Stop all workers and wait up to `timeout` for them to exit.
+    /// Fails if some workers are still busy after that.
+    pub fn shutdown(&self, timeout: Duration, mode: ShutdownMode) -> crate::error::Result<()> {
+        if !self.close() {
+            return Ok(());
+        }
+        let deadline = Instant::now() + timeout;
+
+        if mode == ShutdownMode::Cancel {
+            while self.receiver.try_job().is_some() {
+                self.state.counters.dequeue();
+            }
+        }
+        // taken once no job waits, so with `Drain` every job left runs first
+        for _ in 0..self.state.workers() {
+            let _ = self.sender.control().send(Message::Terminate);
+        }
+
+        let result = loop {
+            let running = self
+                .spawner
+                .workers
+                .lock()
+                .unwrap()
+                .iter_mut()
+                .map(Worker::try_join)
+                .filter(|&running| running)
+                .count();
+            if running == 0 {
+                break Ok(());
+            }
+            if Instant::now() >= deadline {
+                break Err(Error::from(ErrorKind::ThreadPoolError(format!(
+                    "{} workers still running after {:?}",
+                    running, timeout
+                ))));
+            }
+            thread::sleep(JOIN_POLL_INTERVAL);
+        };
+
+        let _ = self.supervisor_sender.send(Message::Terminate);
+        result
+    }
+
+    // mark pool closed, false if it was already
+    fn close(&self) -> bool {
+        let _workers = self.spawner.workers.lock().unwrap();
+        !self.state.closed.swap(true, Ordering::SeqCst)
+    }
+
+    // start another worker if jobs wait while every worker is busy
+    fn grow(&self) {
+        let stats = self.stats();
+        if stats.queued > stats.idle
+            && self
+                .state
+                .reserve_below(self.state.max.load(Ordering::SeqCst))
+        {
+            self.spawner.spawn();
         }
     }
+
+    fn send<F>(
+        &self,
+        priority: Priority,
+        name: Option<String>,
+        deadline: Option<Instant>,
+        job: F,
+    ) -> crate::error::Result<()>
+    where
+        F: Send + FnOnce() + 'static,
+    {
+        self.state.counters.queue();
+        let task = self.task(name, deadline, job);
+        self.sender.jobs(priority).send(task).map_err(|err| {
+            self.state.counters.dequeue();
+            Error::from(err)
+        })?;
+        self.grow();
+        Ok(())
+    }
+
+    fn task<F>(&self, name: Option<String>, deadline: Option<Instant>, job: F) -> Message
+    where
+        F: Send + FnOnce() + 'static,
+    {
+        Message::Work(Task {
+            id: self.next_job.fetch_add(1, Ordering::SeqCst),
+            name,
+            deadline,
+            job: Box::new(job),
+        })
+    }
 }
-// destroy threads when pool is dead
+// destroy threads when pool is dead, without waiting for them, see `shutdown`
 impl Drop for QueueThreadPool {
     fn drop(&mut self) {
-        for _ in 0..self.size {
+        if !self.close() {
+            return;
+        }
+        for _ in 0..self.state.workers() {
             self.sender
+                .control()
                 .send(Message::Terminate)
                 .expect("unable to terminate threads");
         }
@@ -62,33 +432,71 @@ impl Drop for QueueThreadPool {
     }
 }
 
-#[derive(Clone)]
 pub struct JobReceiver {
-    receiver: Receiver<Message>,
+    receiver: QueueReceiver,
     // notify Supervisor
     notifier: Sender<Message>,
     id: usize,
+    state: Arc<PoolState>,
+    // left the pool through `PoolState::retire`, which counted it already
+    retired: bool,
 }
 
 impl JobReceiver {
-    pub fn new(receiver: Receiver<Message>, notifier: Sender<Message>, id: usize) -> Self {
+    pub fn new(
+        receiver: QueueReceiver,
+        notifier: Sender<Message>,
+        id: usize,
+        state: Arc<PoolState>,
+    ) -> Self {
         JobReceiver {
             receiver,
             notifier,
             id,
+            state,
+            retired: false,
         }
     }
-    pub fn receiver(&self) -> &Receiver<Message> {
+    pub fn receiver(&self) -> &QueueReceiver {
         &self.receiver
     }
+
+    // run task, reporting a panic before it takes worker down
+    fn run(&self, task: Task) {
+        let Task {
+            id,
+            name,
+            deadline,
+            job,
+        } = task;
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            // too late to be of use, dropping it cancels its handle
+            self.state.counters.expire();
+            return;
+        }
+        if let Err(payload) = self.state.counters.run(job) {
+            let handler = self.state.panic_handler.read().unwrap().clone();
+            if let Some(handler) = handler {
+                handler(&JobPanic {
+                    job: id,
+                    name: name.as_deref(),
+                    worker: self.id,
+                    payload: payload.as_ref(),
+                });
+            }
+            panic::resume_unwind(payload);
+        }
+    }
 }
 
 impl Drop for JobReceiver {
     fn drop(&mut self) {
+        if !self.retired {
+            self.state.live.fetch_sub(1, Ordering::SeqCst);
+        }
         if thread::panicking() {
-            self.notifier
-                .send(Message::Dead(self.id))
-                .expect("unable to revive thread: cannot send message to supervisor");
+            // supervisor is gone once pool is dropped, then there is nothing to revive
+            let _ = self.notifier.send(Message::Dead(self.id));
         }
     }
 }
@@ -109,19 +517,46 @@ impl Worker {
             thread: Some(thread),
         }
     }
+
+    // join thread if it has exited, tell whether it is still running
+    fn try_join(&mut self) -> bool {
+        match &self.thread {
+            Some(thread) if !thread.is_finished() => true,
+            Some(_) => {
+                // a worker killed by a panicking job was reported already
+                let _ = self.thread.take().map(JoinHandle::join);
+                false
+            }
+            None => false,
+        }
+    }
 }
 
 // complete job
-fn do_job(receiver: JobReceiver) {
+fn do_job(mut receiver: JobReceiver) {
     // listen to job message
     loop {
-        if let Ok(message) = receiver.receiver().recv() {
-            match message {
-                Message::Dead(_) => break,
-                Message::Work(job) => {
-                    job();
+        // workers at the minimum are only let go by `resize`
+        let message = match receiver.receiver().recv(receiver.state.keep_alive()) {
+            Ok(message) => message,
+            // idle for too long
+            Err(RecvTimeoutError::Timeout) => Message::Retire,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        match message {
+            Message::Dead(_) => break,
+            Message::Work(task) => receiver.run(task),
+            Message::Retire if receiver.state.retire() => {
+                receiver.retired = true;
+                break;
+            }
+            Message::Retire => continue,
+            Message::Terminate => {
+                // jobs sent while shutdown began still run
+                while let Some(Message::Work(task)) = receiver.receiver().try_job() {
+                    receiver.run(task);
                 }
-                Message::Terminate => break,
+                break;
             }
         }
     }
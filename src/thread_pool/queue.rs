@@ -0,0 +1,98 @@
+use super::Message;
+use crossbeam::channel::{
+    bounded, unbounded, Receiver, RecvTimeoutError, Select, Sender, TryRecvError,
+};
+use std::time::{Duration, Instant};
+
+/// How urgent a job is, workers take more urgent jobs first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Priority {
+    High,
+    #[default]
+    Normal,
+    Low,
+}
+
+const PRIORITIES: usize = 3;
+
+// one channel per priority, then one for control messages
+pub fn queue(capacity: Option<usize>) -> (QueueSender, QueueReceiver) {
+    let (mut senders, mut receivers) = (Vec::new(), Vec::new());
+    for index in 0..=PRIORITIES {
+        let (sender, receiver) = match capacity {
+            Some(capacity) if index < PRIORITIES => bounded(capacity),
+            _ => unbounded(),
+        };
+        senders.push(sender);
+        receivers.push(receiver);
+    }
+    (QueueSender { senders }, QueueReceiver { receivers })
+}
+
+pub struct QueueSender {
+    senders: Vec<Sender<Message>>,
+}
+
+impl QueueSender {
+    pub fn jobs(&self, priority: Priority) -> &Sender<Message> {
+        &self.senders[priority as usize]
+    }
+
+    // `Terminate` and `Retire` are taken only when no job waits
+    pub fn control(&self) -> &Sender<Message> {
+        &self.senders[PRIORITIES]
+    }
+}
+
+#[derive(Clone)]
+pub struct QueueReceiver {
+    receivers: Vec<Receiver<Message>>,
+}
+
+impl QueueReceiver {
+    // take a waiting job, most urgent first
+    pub fn try_job(&self) -> Option<Message> {
+        self.receivers[..PRIORITIES]
+            .iter()
+            .find_map(|receiver| receiver.try_recv().ok())
+    }
+
+    fn try_recv(&self) -> Result<Message, TryRecvError> {
+        let mut result = Err(TryRecvError::Disconnected);
+        for receiver in &self.receivers {
+            match receiver.try_recv() {
+                Ok(message) => return Ok(message),
+                Err(TryRecvError::Empty) => result = Err(TryRecvError::Empty),
+                Err(TryRecvError::Disconnected) => {}
+            }
+        }
+        result
+    }
+
+    // wait for next message, `None` waits forever
+    pub fn recv(&self, timeout: Option<Duration>) -> Result<Message, RecvTimeoutError> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut select = Select::new();
+        for receiver in &self.receivers {
+            select.recv(receiver);
+        }
+        loop {
+            match self.try_recv() {
+                Ok(message) => return Ok(message),
+                Err(TryRecvError::Disconnected) => return Err(RecvTimeoutError::Disconnected),
+                Err(TryRecvError::Empty) => {}
+            }
+            // only a hint, another worker may take the message first
+            match deadline {
+                Some(deadline) => {
+                    select
+                        .ready_deadline(deadline)
+                        .map_err(|_| RecvTimeoutError::Timeout)?;
+                }
+                None => {
+                    select.ready();
+                }
+            }
+        }
+    }
+}
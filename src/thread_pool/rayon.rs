@@ -0,0 +1,44 @@
+use super::{stats::Counters, PoolStats, ThreadPool};
+use crate::error::{Error, ErrorKind, Result};
+use std::sync::Arc;
+
+/// Runs jobs on a rayon thread pool
+pub struct RayonThreadPool {
+    pool: rayon::ThreadPool,
+    counters: Arc<Counters>,
+}
+
+impl ThreadPool for RayonThreadPool {
+    fn new(size: usize) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(size)
+            // rayon aborts on panics by default, keep serving other jobs instead
+            .panic_handler(|_| {})
+            .build()
+            .map_err(|e| Error::from(ErrorKind::ThreadPoolError(e.to_string())))?;
+        Ok(RayonThreadPool {
+            pool,
+            counters: Arc::new(Counters::default()),
+        })
+    }
+
+    fn execute<F>(&self, job: F) -> Result<()>
+    where
+        F: Send + FnOnce() + 'static,
+    {
+        let counters = Arc::clone(&self.counters);
+        counters.queue();
+        self.pool.spawn(move || {
+            // panic was counted, rayon threads survive it anyway
+            let _ = counters.run(Box::new(job));
+        });
+        Ok(())
+    }
+
+    fn stats(&self) -> PoolStats {
+        self.counters.stats(self.pool.current_num_threads())
+    }
+}
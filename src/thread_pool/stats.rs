@@ -0,0 +1,82 @@
+use super::Job;
+use serde::{Deserialize, Serialize};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// What a thread pool is doing right now, and has done so far
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    pub workers: usize,
+    /// jobs waiting for a worker
+    pub queued: usize,
+    pub busy: usize,
+    pub idle: usize,
+    /// jobs finished, including those which panicked
+    pub executed: u64,
+    pub panics: u64,
+    /// workers started again after a job killed them
+    pub respawns: u64,
+    /// jobs thrown away because their deadline passed before they started
+    #[serde(default)]
+    pub expired: u64,
+}
+
+// counters kept by pools to build `PoolStats`
+#[derive(Default)]
+pub struct Counters {
+    queued: AtomicUsize,
+    busy: AtomicUsize,
+    executed: AtomicU64,
+    panics: AtomicU64,
+    respawns: AtomicU64,
+    expired: AtomicU64,
+}
+
+impl Counters {
+    // a job was queued, call before handing it to workers
+    pub fn queue(&self) {
+        self.queued.fetch_add(1, Ordering::SeqCst);
+    }
+
+    // a queued job was thrown away without running
+    pub fn dequeue(&self) {
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    // a queued job was thrown away at its deadline
+    pub fn expire(&self) {
+        self.dequeue();
+        self.expired.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn respawn(&self) {
+        self.respawns.fetch_add(1, Ordering::SeqCst);
+    }
+
+    // run a queued job, catching a panic so caller can decide what to do with it
+    pub fn run(&self, job: Job) -> std::thread::Result<()> {
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+        self.busy.fetch_add(1, Ordering::SeqCst);
+        let result = panic::catch_unwind(AssertUnwindSafe(job));
+        self.busy.fetch_sub(1, Ordering::SeqCst);
+        self.executed.fetch_add(1, Ordering::SeqCst);
+        if result.is_err() {
+            self.panics.fetch_add(1, Ordering::SeqCst);
+        }
+        result
+    }
+
+    pub fn stats(&self, workers: usize) -> PoolStats {
+        let busy = self.busy.load(Ordering::SeqCst);
+        PoolStats {
+            workers,
+            queued: self.queued.load(Ordering::SeqCst),
+            busy,
+            idle: workers.saturating_sub(busy),
+            executed: self.executed.load(Ordering::SeqCst),
+            panics: self.panics.load(Ordering::SeqCst),
+            respawns: self.respawns.load(Ordering::SeqCst),
+            expired: self.expired.load(Ordering::SeqCst),
+        }
+    }
+}
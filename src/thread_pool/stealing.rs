@@ -0,0 +1,137 @@
+use super::{stats::Counters, Job, PoolStats, ThreadPool};
+use crate::error::Result;
+use crossbeam::deque::{Injector, Stealer, Worker};
+use std::iter;
+use std::sync::atomic::{self, AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// idle workers wake up this often to look for jobs left in other queues
+const PARK_TIMEOUT: Duration = Duration::from_millis(10);
+// times an idle worker looks for jobs again before going to sleep
+const SPINS: usize = 64;
+
+/// Work-stealing pool. New jobs go to a global queue, workers take them
+/// in batches into their own deque and steal from each other when idle.
+pub struct StealingThreadPool {
+    shared: Arc<Shared>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+struct Shared {
+    injector: Injector<Job>,
+    stealers: Vec<Stealer<Job>>,
+    shutdown: AtomicBool,
+    // idle workers sleep here until a job arrives
+    idle: Mutex<()>,
+    wake: Condvar,
+    sleeping: AtomicUsize,
+    counters: Counters,
+}
+
+impl ThreadPool for StealingThreadPool {
+    fn new(size: usize) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        let workers: Vec<Worker<Job>> = (0..size).map(|_| Worker::new_fifo()).collect();
+        let shared = Arc::new(Shared {
+            injector: Injector::new(),
+            stealers: workers.iter().map(Worker::stealer).collect(),
+            shutdown: AtomicBool::new(false),
+            idle: Mutex::new(()),
+            wake: Condvar::new(),
+            sleeping: AtomicUsize::new(0),
+            counters: Counters::default(),
+        });
+
+        let threads = workers
+            .into_iter()
+            .map(|local| {
+                let shared = Arc::clone(&shared);
+                thread::spawn(move || run(local, &shared))
+            })
+            .collect();
+
+        Ok(StealingThreadPool { shared, threads })
+    }
+
+    fn execute<F>(&self, job: F) -> Result<()>
+    where
+        F: Send + FnOnce() + 'static,
+    {
+        self.shared.counters.queue();
+        self.shared.injector.push(Box::new(job));
+        // pairs with fence in `park`, either a sleeping worker is seen or it sees the job
+        atomic::fence(Ordering::SeqCst);
+        if self.shared.sleeping.load(Ordering::SeqCst) > 0 {
+            // take the lock so a worker about to sleep can not miss the job
+            let _idle = self.shared.idle.lock().unwrap();
+            self.shared.wake.notify_one();
+        }
+        Ok(())
+    }
+
+    fn stats(&self) -> PoolStats {
+        self.shared.counters.stats(self.threads.len())
+    }
+}
+
+// let workers finish queued jobs, then wait for them
+impl Drop for StealingThreadPool {
+    fn drop(&mut self) {
+        self.shared.shutdown.store(true, Ordering::SeqCst);
+        {
+            let _idle = self.shared.idle.lock().unwrap();
+            self.shared.wake.notify_all();
+        }
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn run(local: Worker<Job>, shared: &Shared) {
+    let mut spins = 0;
+    loop {
+        match find_job(&local, shared) {
+            // a panicking job must not take worker down with it
+            Some(job) => {
+                spins = 0;
+                let _ = shared.counters.run(job);
+            }
+            None if shared.shutdown.load(Ordering::SeqCst) => break,
+            None if spins < SPINS => {
+                spins += 1;
+                thread::yield_now();
+            }
+            None => park(shared),
+        }
+    }
+}
+
+// sleep until a job arrives, or a while has passed
+fn park(shared: &Shared) {
+    let idle = shared.idle.lock().unwrap();
+    shared.sleeping.fetch_add(1, Ordering::SeqCst);
+    atomic::fence(Ordering::SeqCst);
+    if shared.injector.is_empty() && !shared.shutdown.load(Ordering::SeqCst) {
+        let _ = shared.wake.wait_timeout(idle, PARK_TIMEOUT).unwrap();
+    }
+    shared.sleeping.fetch_sub(1, Ordering::SeqCst);
+}
+
+// own queue first, then a batch from global queue, then steal from other workers
+fn find_job(local: &Worker<Job>, shared: &Shared) -> Option<Job> {
+    local.pop().or_else(|| {
+        iter::repeat_with(|| {
+            shared
+                .injector
+                .steal_batch_and_pop(local)
+                .or_else(|| shared.stealers.iter().map(Stealer::steal).collect())
+        })
+        .find(|steal| !steal.is_retry())
+        .and_then(|steal| steal.success())
+    })
+}
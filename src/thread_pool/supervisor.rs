@@ -1,53 +1,38 @@
-use crossbeam::channel::{Receiver, Sender};
+use crossbeam::channel::Receiver;
+use std::sync::Arc;
 
 use super::{
-    pool::{JobReceiver, Worker},
+    pool::{PoolState, Spawner},
     Message,
 };
 /// It supervises workers
 pub struct Supervisor {
-    workers: Vec<Worker>,
     receiver: Receiver<Message>,
-    sender: Sender<Message>,
-    worker_receiver: Receiver<Message>,
-    size: usize,
+    spawner: Spawner,
+    state: Arc<PoolState>,
 }
 
 impl Supervisor {
-    pub fn new(
-        receiver: Receiver<Message>,
-        sender: Sender<Message>,
-        worker_receiver: Receiver<Message>,
-        size: usize,
-    ) -> Self {
-        let mut workers = Vec::with_capacity(size);
-        for id in 0..size {
-            let job_receiver = JobReceiver::new(worker_receiver.clone(), sender.clone(), id);
-            let worker = Worker::new(id, job_receiver.clone());
-            workers.push(worker);
-        }
+    pub fn new(receiver: Receiver<Message>, spawner: Spawner, state: Arc<PoolState>) -> Self {
         Supervisor {
             receiver,
-            workers,
-            sender,
-            worker_receiver,
-            size,
+            spawner,
+            state,
         }
     }
     // listen to channel
     pub fn watch(&mut self) {
         while let Ok(message) = self.receiver.recv() {
             match message {
-                Message::Dead(id) => {
+                // pool is shutting down, do not revive workers
+                Message::Dead(_) if self.state.is_closed() => continue,
+                Message::Dead(_) => {
                     // spawn a new worker if previous one is dead
-                    let new_id = self.size + id;
-                    let job_receiver =
-                        JobReceiver::new(self.worker_receiver.clone(), self.sender.clone(), id);
-                    let worker = Worker::new(new_id, job_receiver);
-                    // find original place of worker
-                    self.workers[id % self.size] = worker;
+                    self.state.reserve();
+                    self.spawner.spawn();
+                    self.state.counters().respawn();
                 }
-                Message::Work(_) => continue,
+                Message::Work(_) | Message::Retire => continue,
                 Message::Terminate => {
                     break;
                 }
@@ -1,4 +1,5 @@
 use crate::error::Result;
+use std::fs::File;
 use std::io::{BufWriter, Seek, SeekFrom, Write};
 pub struct PosWriter<T: Write + Seek> {
     writer: BufWriter<T>,
@@ -25,6 +26,14 @@ impl<T: Write + Seek> PosWriter<T> {
     }
 }
 
+impl PosWriter<File> {
+    /// flush buffer and wait until data reaches disk
+    pub fn sync(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(self.writer.get_ref().sync_all()?)
+    }
+}
+
 impl<T: Seek + Write> Seek for PosWriter<T> {
     fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
         self.pos = self.writer.seek(pos)?;
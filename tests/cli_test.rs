@@ -273,6 +273,7 @@ fn cli_access_server(engine: &str, addr: &str) {
         .current_dir(&temp_dir)
         .assert()
         .failure()
+        .code(2)
         .stderr(contains("Key not found"));
 
     Command::cargo_bin("kvs-client")
@@ -334,3 +335,242 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+#[test]
+fn cli_thread_pool_options() {
+    for (pool, addr) in &[
+        ("naive", "127.0.0.1:4006"),
+        ("shared", "127.0.0.1:4007"),
+        ("rayon", "127.0.0.1:4008"),
+    ] {
+        let temp_dir = TempDir::new().unwrap();
+        let mut child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--pool", pool, "--threads", "2", "--addr", addr])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", "key1", "value1", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["get", "key1", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout("value1\n");
+
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+    }
+
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--pool", "unknown"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// `kvs-client backup` should leave a copy `kvs-admin restore` can install
+#[test]
+fn cli_backup_restore() {
+    let addr = "127.0.0.1:4121";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--backup-dir", "backups"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    // only under backup dir
+    for dest in ["../backup", "/tmp/backup"] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["backup", dest, "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure();
+    }
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["backup", "backup", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("copied"));
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    let restore_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["restore", "backups/backup"])
+        .arg(restore_dir.path().join("db"))
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("restored"));
+    // only into an empty directory
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["restore", "backups/backup"])
+        .arg(restore_dir.path().join("db"))
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(&restore_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&restore_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    // refused without backup dir
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["backup", "backup", "--addr", addr])
+        .current_dir(&restore_dir)
+        .assert()
+        .failure();
+    assert!(!restore_dir.path().join("backup").exists());
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// Settings should come from flags, then a config file, then defaults
+#[test]
+fn cli_config_file() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(
+        temp_dir.path().join("kvs.toml"),
+        "addr = \"127.0.0.1:4122\"\ndata-dir = \"data\"\nthreads = 4\ndurability = \"sync\"\nmax-connections = 8\n",
+    )
+    .unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args([
+            "--config",
+            "kvs.toml",
+            "--threads",
+            "6",
+            "--idle-timeout",
+            "10",
+            "--print-config",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("addr = \"127.0.0.1:4122\""))
+        .stdout(contains("threads = 6"))
+        .stdout(contains("durability = \"sync\""))
+        .stdout(contains("log-level = \"info\""))
+        .stdout(contains("max-connections = 8"))
+        .stdout(contains("idle-timeout = 10"))
+        .stdout(contains("queue-size = 1024"));
+
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--config", "kvs.toml"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4122"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+    assert!(temp_dir.path().join("data").join("engine").exists());
+    assert!(!temp_dir.path().join("db").exists());
+
+    fs::write(temp_dir.path().join("bad.toml"), "thread = 4\n").unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--config", "bad.toml"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("thread"));
+}
+
+// Passwords should come from stdin, a file or the environment, never from flags
+#[test]
+fn cli_password_sources() {
+    let addr = "127.0.0.1:4123";
+    let temp_dir = TempDir::new().unwrap();
+    let output = assert_cmd::Command::cargo_bin("kvs-server")
+        .unwrap()
+        .arg("--hash-password")
+        .write_stdin("secret\n")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let hash = String::from_utf8(output.stdout).unwrap();
+    fs::write(
+        temp_dir.path().join("users.json"),
+        format!(
+            r#"[{{"name":"alice","password":"{}","rules":[{{"prefix":"","access":"read-write"}}]}}]"#,
+            hash.trim()
+        ),
+    )
+    .unwrap();
+    fs::write(temp_dir.path().join("password"), "secret\n").unwrap();
+
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--users", "users.json"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let client = |args: &[&str]| {
+        let mut command = assert_cmd::Command::cargo_bin("kvs-client").unwrap();
+        command
+            .args(["set", "key1", "value1", "--addr", addr, "--user", "alice"])
+            .args(args)
+            .current_dir(&temp_dir)
+            .env_remove("KVS_PASSWORD");
+        command
+    };
+    client(&[]).env("KVS_PASSWORD", "secret").assert().success();
+    client(&[]).env("KVS_PASSWORD", "wrong").assert().failure();
+    client(&["--password-file", "password"]).assert().success();
+    client(&["--password-file", "-"])
+        .write_stdin("secret\n")
+        .assert()
+        .success();
+    client(&[])
+        .assert()
+        .failure()
+        .stderr(contains("KVS_PASSWORD"));
+    client(&["--password", "secret"]).assert().failure();
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
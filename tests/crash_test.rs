@@ -0,0 +1,91 @@
+use std::env;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use kvs::{common::KvsEngine, error::Result, kvs_store::KvStore, manifest::Manifest};
+use tempfile::TempDir;
+
+// steps of compaction a crash can stop at, in order
+const STEPS: [&str; 6] = [
+    "compact-rolled",
+    "compact-copied",
+    "compact-synced",
+    "compact-renamed",
+    "compact-committed",
+    "compact-removing",
+];
+
+const KEYS: usize = 100;
+const ROUNDS: usize = 5;
+
+// Runs in a child process started by `crash_in_compaction`, which dies in compaction
+#[test]
+fn crash_child() -> Result<()> {
+    let dir = match env::var_os("KVS_CRASH_DIR") {
+        Some(dir) => dir,
+        None => return Ok(()),
+    };
+    let store = KvStore::open(Path::new(&dir))?;
+    for round in 0..ROUNDS {
+        for key in 0..KEYS {
+            store.set(format!("key{}", key), format!("value{}", round))?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+    store.flush()?;
+    store.compact()?;
+    Ok(())
+}
+
+// Store should hold every write and only committed db files after a crash at any step
+#[test]
+fn crash_in_compaction() -> Result<()> {
+    for step in STEPS.iter() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let status = Command::new(env::current_exe()?)
+            .args(["crash_child", "--exact", "--nocapture"])
+            .env("KVS_CRASH_DIR", temp_dir.path())
+            .env("KVS_CRASH_AT", step)
+            .stdout(Stdio::null())
+            .status()?;
+        assert!(!status.success(), "child did not crash at {}", step);
+
+        check_store(temp_dir.path(), step)?;
+        // compacting again after recovery works too
+        let store = KvStore::open(temp_dir.path())?;
+        store.compact()?;
+        drop(store);
+        check_store(temp_dir.path(), step)?;
+    }
+    Ok(())
+}
+
+fn check_store(dir: &Path, step: &str) -> Result<()> {
+    let store = KvStore::open(dir)?;
+    assert_eq!(store.get("key0".to_owned())?, None, "at {}", step);
+    for key in 1..KEYS {
+        assert_eq!(
+            store.get(format!("key{}", key))?,
+            Some(format!("value{}", ROUNDS - 1)),
+            "at {}",
+            step
+        );
+    }
+    drop(store);
+
+    let manifest = Manifest::load(dir)?.expect("manifest");
+    let mut files: Vec<String> = dir
+        .read_dir()?
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .filter(|name| name.ends_with(".db") || name.ends_with(".tmp"))
+        .collect();
+    files.sort();
+    let mut listed: Vec<String> = manifest
+        .segments
+        .iter()
+        .map(|no| format!("{}.db", no))
+        .collect();
+    listed.sort();
+    assert_eq!(files, listed, "at {}", step);
+    Ok(())
+}
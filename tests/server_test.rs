@@ -0,0 +1,608 @@
+use std::fs;
+use std::io::Write;
+use std::net::{SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use kvs::{
+    auth::{self, Access, Rule, User, Users},
+    client::Client,
+    common::KvsEngine,
+    error::{ErrorKind, Result},
+    kvs_store::KvStore,
+    raft::{ClusterClient, RaftNode, Role},
+    replication::Follower,
+    server::{Server, ShutdownHandle},
+    shard::ShardedClient,
+    thread_pool::{QueueThreadPool, ThreadPool},
+    tls,
+};
+use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+use serde_json::{Deserializer, Value};
+use slog::{o, Discard, Logger};
+use tempfile::TempDir;
+
+// start a server on a background thread
+fn spawn_server(addr: SocketAddr, temp_dir: &TempDir) -> Result<()> {
+    spawn_server_with(addr, temp_dir, |server| server)
+}
+
+// start a server configured by `configure`
+fn spawn_server_with<F>(addr: SocketAddr, temp_dir: &TempDir, configure: F) -> Result<()>
+where
+    F: FnOnce(Server<KvStore, QueueThreadPool>) -> Server<KvStore, QueueThreadPool>
+        + Send
+        + 'static,
+{
+    let store = KvStore::open(temp_dir.path())?;
+    let pool = QueueThreadPool::new(4)?;
+    thread::spawn(move || {
+        let mut server = configure(Server::new(store, pool));
+        server
+            .serve(&addr, Logger::root(Discard, o!()))
+            .expect("server failed");
+    });
+    thread::sleep(Duration::from_millis(300));
+    Ok(())
+}
+
+#[test]
+fn typed_errors_over_the_wire() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4100".parse().unwrap();
+    spawn_server(addr, &temp_dir)?;
+
+    let mut client = Client::connect(addr)?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+
+    let err = client.remove("key1".to_owned()).unwrap_err();
+    assert!(matches!(err.kind(), ErrorKind::KeyNotFound(_)));
+
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.remove("key1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn handshake_agrees_on_version() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4101".parse().unwrap();
+    spawn_server(addr, &temp_dir)?;
+
+    let client = Client::connect(addr)?;
+    assert_eq!(client.version(), 1);
+    assert!(client.supports("typed-errors"));
+    assert!(!client.supports("no-such-feature"));
+    Ok(())
+}
+
+#[test]
+fn unknown_request_gets_error_response() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4102".parse().unwrap();
+    spawn_server(addr, &temp_dir)?;
+
+    let stream = TcpStream::connect(addr)?;
+    let mut writer = &stream;
+    let mut responses = Deserializer::from_reader(&stream).into_iter::<Value>();
+
+    writer.write_all(br#"{"Frobnicate":{"key":"key1"}}"#)?;
+    let response = responses.next().unwrap()?;
+    assert!(response["Error"]["InvalidRequest"].is_string());
+
+    // connection is still usable after an unknown request
+    writer.write_all(br#"{"Handshake":{"version":0,"features":[]}}"#)?;
+    let response = responses.next().unwrap()?;
+    assert!(response["Handshake"]["Err"]["UnsupportedVersion"].is_string());
+
+    writer.write_all(br#"{"Get":{"key":"key1"}}"#)?;
+    let response = responses.next().unwrap()?;
+    assert!(response["Get"]["Ok"].is_null());
+    Ok(())
+}
+
+#[test]
+fn multi_key_requests() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4103".parse().unwrap();
+    spawn_server(addr, &temp_dir)?;
+
+    let mut client = Client::connect(addr)?;
+    assert!(client.supports("multi"));
+
+    let pairs = (0..100)
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .collect();
+    client.set_many(pairs)?;
+
+    let keys = vec!["key42".to_owned(), "missing".to_owned(), "key7".to_owned()];
+    assert_eq!(
+        client.get_many(keys.clone())?,
+        vec![Some("value42".to_owned()), None, Some("value7".to_owned())]
+    );
+
+    assert_eq!(client.remove_many(keys.clone())?, vec![true, false, true]);
+    assert_eq!(client.get_many(keys)?, vec![None, None, None]);
+    assert_eq!(client.get("key8".to_owned())?, Some("value8".to_owned()));
+    Ok(())
+}
+
+// write a CA plus server and client certificates signed by it
+fn generate_certs(dir: &Path) -> PathBuf {
+    let write = |name: &str, content: String| fs::write(dir.join(name), content).unwrap();
+
+    let mut ca_params = CertificateParams::new(vec![]).unwrap();
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca_key = KeyPair::generate().unwrap();
+    let ca = ca_params.self_signed(&ca_key).unwrap();
+    write("ca.pem", ca.pem());
+
+    for name in &["server", "client"] {
+        let params = CertificateParams::new(vec!["localhost".to_owned()]).unwrap();
+        let key = KeyPair::generate().unwrap();
+        let cert = params.signed_by(&key, &ca, &ca_key).unwrap();
+        write(&format!("{}.pem", name), cert.pem());
+        write(&format!("{}.key", name), key.serialize_pem());
+    }
+    dir.to_path_buf()
+}
+
+#[test]
+fn tls_connection() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let certs = generate_certs(temp_dir.path());
+    let addr: SocketAddr = "127.0.0.1:4104".parse().unwrap();
+    let config = tls::server_config(&certs.join("server.pem"), &certs.join("server.key"), None)?;
+    spawn_server_with(addr, &temp_dir, |server| server.with_tls(config))?;
+
+    let config = tls::client_config(&certs.join("ca.pem"), None)?;
+    let mut client = Client::connect_tls(addr, "localhost", Arc::clone(&config))?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // certificate is not issued for this name
+    assert!(Client::connect_tls(addr, "example.com", Arc::clone(&config)).is_err());
+    // plaintext clients are not served
+    assert!(Client::connect(addr).is_err());
+    Ok(())
+}
+
+#[test]
+fn tls_client_authentication() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let certs = generate_certs(temp_dir.path());
+    let addr: SocketAddr = "127.0.0.1:4105".parse().unwrap();
+    let config = tls::server_config(
+        &certs.join("server.pem"),
+        &certs.join("server.key"),
+        Some(&certs.join("ca.pem")),
+    )?;
+    spawn_server_with(addr, &temp_dir, |server| server.with_tls(config))?;
+
+    let anonymous = tls::client_config(&certs.join("ca.pem"), None)?;
+    assert!(Client::connect_tls(addr, "localhost", anonymous).is_err());
+
+    let identity = (certs.join("client.pem"), certs.join("client.key"));
+    let config = tls::client_config(&certs.join("ca.pem"), Some((&identity.0, &identity.1)))?;
+    let mut client = Client::connect_tls(addr, "localhost", config)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn authentication_and_access_control() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4106".parse().unwrap();
+    let rule = |prefix: &str, access| Rule {
+        prefix: prefix.to_owned(),
+        access,
+    };
+    let users = Users::new(vec![User {
+        name: "alice".to_owned(),
+        password: auth::hash_password("secret"),
+        rules: vec![
+            rule("", Access::ReadOnly),
+            rule("alice:", Access::ReadWrite),
+        ],
+    }]);
+    spawn_server_with(addr, &temp_dir, |server| server.with_users(users))?;
+
+    let mut client = Client::connect(addr)?;
+    let err = client.get("key1".to_owned()).unwrap_err();
+    assert!(matches!(err.kind(), ErrorKind::Unauthenticated(_)));
+    let err = client
+        .auth("alice".to_owned(), "wrong".to_owned())
+        .unwrap_err();
+    assert!(matches!(err.kind(), ErrorKind::Unauthenticated(_)));
+
+    client.auth("alice".to_owned(), "secret".to_owned())?;
+    client.set("alice:key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(
+        client.get("alice:key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(client.get("key1".to_owned())?, None);
+
+    let err = client
+        .set("key1".to_owned(), "value1".to_owned())
+        .unwrap_err();
+    assert!(matches!(err.kind(), ErrorKind::PermissionDenied(_)));
+    let err = client
+        .remove_many(vec!["alice:key1".to_owned(), "key1".to_owned()])
+        .unwrap_err();
+    assert!(matches!(err.kind(), ErrorKind::PermissionDenied(_)));
+    assert_eq!(
+        client.get("alice:key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    Ok(())
+}
+
+#[test]
+fn unix_socket() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("kvs.sock");
+    // a socket left behind by an earlier run must not stop server from starting
+    drop(std::os::unix::net::UnixListener::bind(&path)?);
+
+    let store = KvStore::open(temp_dir.path())?;
+    let pool = QueueThreadPool::new(4)?;
+    let server_path = path.clone();
+    thread::spawn(move || {
+        Server::new(store, pool)
+            .serve_unix(&server_path, Logger::root(Discard, o!()))
+            .expect("server failed");
+    });
+    thread::sleep(Duration::from_millis(300));
+
+    let mut client = Client::connect_unix(&path)?;
+    assert_eq!(client.version(), 1);
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.remove("key1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn graceful_shutdown() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4107".parse().unwrap();
+
+    let store = KvStore::open(temp_dir.path())?;
+    let pool = QueueThreadPool::new(4)?;
+    let mut server = Server::new(store, pool).with_shutdown_timeout(Duration::from_secs(5));
+    let handle = server.shutdown_handle();
+    let server = thread::spawn(move || server.serve(&addr, Logger::root(Discard, o!())));
+    thread::sleep(Duration::from_millis(300));
+
+    let mut client = Client::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+
+    // an idle connection must not hold up shutdown until timeout
+    let start = Instant::now();
+    handle.shutdown();
+    server.join().expect("server panicked")?;
+    assert!(start.elapsed() < Duration::from_secs(2));
+
+    assert!(client.get("key1".to_owned()).is_err());
+    assert!(Client::connect(addr).is_err());
+
+    drop(client);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn connection_limits() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4108".parse().unwrap();
+    spawn_server_with(addr, &temp_dir, |server| {
+        // handshake listing every feature must still fit
+        server.with_max_connections(1).with_max_request_size(256)
+    })?;
+
+    let mut client = Client::connect(addr)?;
+    let err = Client::connect(addr).err().expect("second client accepted");
+    assert!(matches!(err.kind(), ErrorKind::Overloaded(_)));
+
+    let err = client.set("key1".to_owned(), "v".repeat(500)).unwrap_err();
+    assert!(matches!(err.kind(), ErrorKind::InvalidCommand(_)));
+
+    // slot is free again once first client is gone
+    drop(client);
+    thread::sleep(Duration::from_millis(100));
+    let mut client = Client::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    Ok(())
+}
+
+#[test]
+fn idle_connections_are_closed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4109".parse().unwrap();
+    spawn_server_with(addr, &temp_dir, |server| {
+        server.with_idle_timeout(Duration::from_millis(200))
+    })?;
+
+    let mut client = Client::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    thread::sleep(Duration::from_millis(500));
+    assert!(client.get("key1".to_owned()).is_err());
+    Ok(())
+}
+
+#[test]
+fn full_queue_rejects_connections() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4110".parse().unwrap();
+
+    let store = KvStore::open(temp_dir.path())?;
+    // one worker and room for one waiting connection
+    let pool = QueueThreadPool::bounded(1, 1)?;
+    thread::spawn(move || {
+        Server::new(store, pool)
+            .serve(&addr, Logger::root(Discard, o!()))
+            .expect("server failed");
+    });
+    thread::sleep(Duration::from_millis(300));
+
+    let mut busy = Client::connect(addr)?;
+    let _queued = TcpStream::connect(addr)?;
+    thread::sleep(Duration::from_millis(200));
+
+    let err = Client::connect(addr).err().expect("client accepted");
+    assert!(matches!(err.kind(), ErrorKind::Overloaded(_)));
+    busy.set("key1".to_owned(), "value1".to_owned())?;
+    Ok(())
+}
+
+#[test]
+fn server_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4111".parse().unwrap();
+    spawn_server(addr, &temp_dir)?;
+
+    let mut client = Client::connect(addr)?;
+    let _other = Client::connect(addr)?;
+    let stats = client.stats()?;
+    assert_eq!(stats.connections, 2);
+    // each connection keeps a worker busy
+    assert_eq!(stats.pool.workers, 4);
+    assert_eq!(stats.pool.busy, 2);
+    assert_eq!(stats.pool.idle, 2);
+    assert_eq!(stats.pool.panics, 0);
+    Ok(())
+}
+
+// wait until `check` passes, for changes which reach a follower eventually
+fn eventually<F: FnMut() -> Result<bool>>(mut check: F) -> Result<()> {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !check()? {
+        assert!(Instant::now() < deadline, "condition not met in time");
+        thread::sleep(Duration::from_millis(50));
+    }
+    Ok(())
+}
+
+#[test]
+fn replication() -> Result<()> {
+    let leader_dir = TempDir::new().expect("unable to create temporary working directory");
+    let follower_dir = TempDir::new().expect("unable to create temporary working directory");
+    let leader_addr: SocketAddr = "127.0.0.1:4112".parse().unwrap();
+    let follower_addr: SocketAddr = "127.0.0.1:4113".parse().unwrap();
+
+    // writes made before compaction reach follower in a snapshot
+    let store = KvStore::open(leader_dir.path())?;
+    store.set("old".to_owned(), "value".to_owned())?;
+    store.compact()?;
+    drop(store);
+    spawn_server(leader_addr, &leader_dir)?;
+    let mut leader = Client::connect(leader_addr)?;
+    leader.set("key1".to_owned(), "value1".to_owned())?;
+
+    let store = KvStore::open(follower_dir.path())?;
+    let follower_store = store.clone();
+    let pool = QueueThreadPool::new(4)?;
+    let mut server =
+        Server::new(store.clone(), pool).with_follower(Follower::new(store, leader_addr));
+    let shutdown = server.shutdown_handle();
+    let handle = thread::spawn(move || server.serve(&follower_addr, Logger::root(Discard, o!())));
+    thread::sleep(Duration::from_millis(300));
+
+    let mut follower = Client::connect(follower_addr)?;
+    eventually(|| Ok(follower.get("key1".to_owned())? == Some("value1".to_owned())))?;
+    assert_eq!(follower.get("old".to_owned())?, Some("value".to_owned()));
+
+    leader.set("key2".to_owned(), "value2".to_owned())?;
+    leader.remove("key1".to_owned())?;
+    eventually(|| Ok(follower.get("key1".to_owned())?.is_none()))?;
+    assert_eq!(follower.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    let err = follower
+        .set("key3".to_owned(), "value3".to_owned())
+        .unwrap_err();
+    assert!(matches!(err.kind(), ErrorKind::ReadOnly(_)));
+
+    shutdown.shutdown();
+    handle.join().unwrap()?;
+    assert_eq!(follower_store.last_seq(), 4);
+    Ok(())
+}
+
+// start member `addr` of a raft cluster starting with `members`
+fn spawn_raft_node(
+    addr: SocketAddr,
+    temp_dir: &TempDir,
+    members: &[SocketAddr],
+) -> Result<(RaftNode<KvStore>, KvStore, ShutdownHandle)> {
+    let store = KvStore::open(temp_dir.path())?;
+    let node = RaftNode::open(addr, &temp_dir.path().join("raft"), store.clone(), members)?;
+    let mut server = Server::new(store.clone(), QueueThreadPool::new(4)?).with_raft(node.clone());
+    let shutdown = server.shutdown_handle();
+    thread::spawn(move || server.serve(&addr, Logger::root(Discard, o!())));
+    Ok((node, store, shutdown))
+}
+
+#[test]
+fn raft_cluster() -> Result<()> {
+    let addrs: Vec<SocketAddr> = (4114..4117)
+        .map(|port| format!("127.0.0.1:{}", port).parse().unwrap())
+        .collect();
+    let dirs: Vec<TempDir> = (0..4)
+        .map(|_| TempDir::new().expect("unable to create temporary working directory"))
+        .collect();
+    let nodes = addrs
+        .iter()
+        .zip(&dirs)
+        .map(|(addr, dir)| spawn_raft_node(*addr, dir, &addrs))
+        .collect::<Result<Vec<_>>>()?;
+
+    let leader = || {
+        nodes
+            .iter()
+            .position(|(node, ..)| node.role() == Role::Leader)
+    };
+    eventually(|| Ok(leader().is_some()))?;
+    let first = leader().unwrap();
+    let follower = (first + 1) % nodes.len();
+    eventually(|| Ok(nodes[follower].0.leader().is_some()))?;
+
+    // followers point clients at the leader
+    let mut client = Client::connect(addrs[follower])?;
+    let err = client
+        .set("key1".to_owned(), "value1".to_owned())
+        .unwrap_err();
+    assert!(
+        matches!(err.kind(), ErrorKind::NotLeader(_, Some(leader)) if *leader == addrs[first].to_string())
+    );
+    let mut cluster = ClusterClient::new(vec![addrs[follower]]);
+    cluster.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(cluster.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // enough writes for applied entries to be dropped from every log
+    let pairs: Vec<(String, String)> = (0..1100)
+        .map(|i| (format!("many{}", i), format!("value{}", i)))
+        .collect();
+    cluster.call(|client| client.set_many(pairs.clone()))?;
+    for (_, store, _) in &nodes {
+        eventually(|| Ok(store.get("many1099".to_owned())? == Some("value1099".to_owned())))?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    }
+
+    // a new member catches up from a snapshot of the store
+    let new_addr: SocketAddr = "127.0.0.1:4117".parse().unwrap();
+    let (new_node, new_store, new_shutdown) = spawn_raft_node(new_addr, &dirs[3], &[])?;
+    let members = cluster.add_member(new_addr.to_string())?;
+    assert_eq!(members.len(), 4);
+    eventually(|| Ok(new_store.get("many0".to_owned())? == Some("value0".to_owned())))?;
+    assert_eq!(new_node.members(), members);
+
+    // remaining members elect a new leader when it goes away
+    let (old_leader, _, shutdown) = &nodes[first];
+    shutdown.shutdown();
+    eventually(|| {
+        Ok(leader().is_some_and(|leader| leader != first) || new_node.role() == Role::Leader)
+    })?;
+    assert_ne!(old_leader.role(), Role::Leader);
+    cluster.set("key2".to_owned(), "value2".to_owned())?;
+    cluster.remove("key1".to_owned())?;
+    let members = cluster.remove_member(addrs[first].to_string())?;
+    assert_eq!(members.len(), 3);
+    eventually(|| Ok(new_store.get("key1".to_owned())?.is_none()))?;
+    assert_eq!(new_store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // a leader cut off from the other members no longer serves reads
+    let mut members = vec![(&new_node, &new_shutdown)];
+    members.extend(
+        nodes
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != first)
+            .map(|(_, (node, _, shutdown))| (node, shutdown)),
+    );
+    eventually(|| Ok(members.iter().any(|(node, _)| node.role() == Role::Leader)))?;
+    let (cut_off, _) = members
+        .iter()
+        .find(|(node, _)| node.role() == Role::Leader)
+        .unwrap();
+    for (node, shutdown) in &members {
+        if node.id() != cut_off.id() {
+            shutdown.shutdown();
+        }
+    }
+    thread::sleep(Duration::from_millis(200));
+    assert!(cut_off.check_leader().is_ok());
+    assert!(cut_off.confirm_leader().is_err());
+
+    new_shutdown.shutdown();
+    for (_, _, shutdown) in &nodes {
+        shutdown.shutdown();
+    }
+    Ok(())
+}
+
+#[test]
+fn sharding() -> Result<()> {
+    let dirs: Vec<TempDir> = (0..3)
+        .map(|_| TempDir::new().expect("unable to create temporary working directory"))
+        .collect();
+    let addrs: Vec<SocketAddr> = (4118..4121)
+        .map(|port| format!("127.0.0.1:{}", port).parse().unwrap())
+        .collect();
+    for (addr, dir) in addrs.iter().zip(&dirs) {
+        spawn_server(*addr, dir)?;
+    }
+
+    let mut sharded = ShardedClient::new(&addrs[..2]);
+    let keys: Vec<String> = (0..300).map(|i| format!("key{}", i)).collect();
+    let pairs = keys
+        .iter()
+        .map(|key| (key.clone(), format!("{}-value", key)));
+    sharded.set_many(pairs.collect())?;
+    sharded.set("single".to_owned(), "value".to_owned())?;
+    assert_eq!(sharded.get("single".to_owned())?, Some("value".to_owned()));
+
+    // every node got a share, and holds only keys the ring gives it
+    for addr in &addrs[..2] {
+        let stored = Client::connect(*addr)?.scan(None, 1000)?;
+        assert!(stored.len() > 50, "{} holds {} keys", addr, stored.len());
+        assert!(stored
+            .iter()
+            .all(|(key, _)| sharded.ring().node_for(key) == Some(*addr)));
+    }
+
+    // only keys owned by the new node move
+    let moved = sharded.add_node(addrs[2])?;
+    let on_new = Client::connect(addrs[2])?.scan(None, 1000)?;
+    assert_eq!(moved, on_new.len());
+    assert!(moved > 0 && moved < 200, "moved {} keys", moved);
+    let values = sharded.get_many(keys.clone())?;
+    for (key, value) in keys.iter().zip(values) {
+        assert_eq!(value, Some(format!("{}-value", key)));
+    }
+    let total: usize = addrs
+        .iter()
+        .map(|addr| Ok(Client::connect(*addr)?.scan(None, 1000)?.len()))
+        .sum::<Result<usize>>()?;
+    assert_eq!(total, 301);
+
+    let removed = sharded.remove_many(vec!["key1".to_owned(), "missing".to_owned()])?;
+    assert_eq!(removed, vec![true, false]);
+
+    // a node leaving hands all of its keys over
+    sharded.remove_node(addrs[0])?;
+    assert!(Client::connect(addrs[0])?.scan(None, 1000)?.is_empty());
+    assert_eq!(sharded.get("single".to_owned())?, Some("value".to_owned()));
+    assert_eq!(
+        sharded.get("key2".to_owned())?,
+        Some("key2-value".to_owned())
+    );
+    Ok(())
+}
@@ -1,6 +1,15 @@
+use std::fs::OpenOptions;
+use std::io::Write;
 use std::path::Path;
 
-use kvs::{common::KvsEngine, error::Result, kvs_store::KvStore};
+use kvs::{
+    common::KvsEngine,
+    error::{ErrorKind, Result},
+    export::{self, Format},
+    inspect::{self, Repair},
+    kvs_store::{Durability, KvStore},
+    manifest::{Manifest, FORMAT_VERSION},
+};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -81,6 +90,30 @@ fn remove_key() -> Result<()> {
     Ok(())
 }
 
+// Only one of many concurrent removes of a key succeeds
+#[test]
+fn concurrent_remove() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for round in 0..50 {
+        store.set("key1".to_owned(), format!("value{}", round))?;
+        let removers: Vec<_> = (0..4)
+            .map(|_| {
+                let store = store.clone();
+                std::thread::spawn(move || store.remove("key1".to_owned()).is_ok())
+            })
+            .collect();
+        let removed = removers
+            .into_iter()
+            .map(|remover| remover.join().unwrap())
+            .filter(|&ok| ok)
+            .count();
+        assert_eq!(removed, 1);
+    }
+    assert_eq!(store.last_seq(), 100);
+    Ok(())
+}
+
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
 #[test]
@@ -126,3 +159,537 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+#[test]
+fn bulk_operations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set_many(vec![
+        ("key1".to_owned(), "value1".to_owned()),
+        ("key2".to_owned(), "value2".to_owned()),
+        ("key1".to_owned(), "value3".to_owned()),
+    ])?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+
+    let keys = vec![
+        "key4".to_owned(),
+        "key1".to_owned(),
+        "key3".to_owned(),
+        "key2".to_owned(),
+    ];
+    let expected = vec![
+        Some("value4".to_owned()),
+        Some("value3".to_owned()),
+        None,
+        Some("value2".to_owned()),
+    ];
+    assert_eq!(store.get_many(keys.clone())?, expected);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_many(keys.clone())?, expected);
+
+    assert_eq!(
+        store.remove_many(vec!["key1".to_owned(), "key3".to_owned()])?,
+        vec![true, false]
+    );
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Writes are numbered and can be replayed on another store
+#[test]
+fn replication_log() -> Result<()> {
+    let leader_dir = TempDir::new().expect("unable to create temporary working directory");
+    let follower_dir = TempDir::new().expect("unable to create temporary working directory");
+    let leader = KvStore::open(leader_dir.path())?;
+    let follower = KvStore::open(follower_dir.path())?;
+
+    leader.set("key1".to_owned(), "value1".to_owned())?;
+    leader.set("key2".to_owned(), "value2".to_owned())?;
+    leader.remove("key1".to_owned())?;
+    assert_eq!(leader.last_seq(), 3);
+
+    let changes = leader.changes_since(0, 100)?.unwrap();
+    assert_eq!(
+        changes.iter().map(|c| c.seq()).collect::<Vec<_>>(),
+        vec![1, 2, 3]
+    );
+    assert_eq!(leader.changes_since(1, 1)?.unwrap().len(), 1);
+    assert_eq!(leader.changes_since(3, 100)?, Some(vec![]));
+    assert_eq!(leader.changes_since(5, 100)?, None);
+
+    // applying twice changes nothing
+    follower.apply(changes.clone())?;
+    follower.apply(changes)?;
+    assert_eq!(follower.last_seq(), 3);
+    assert_eq!(follower.get("key1".to_owned())?, None);
+    assert_eq!(follower.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // numbering survives reopening, changes are read from db files then
+    drop(leader);
+    let leader = KvStore::open(leader_dir.path())?;
+    assert_eq!(leader.last_seq(), 3);
+    assert_eq!(leader.changes_since(1, 100)?.unwrap().len(), 2);
+    // only from db files listed in manifest
+    std::fs::write(
+        leader_dir.path().join("9.db"),
+        r#"{"Set":{"key":"stray","value":"value","seq":2}}"#,
+    )?;
+    assert_eq!(leader.changes_since(1, 100)?.unwrap().len(), 2);
+    std::fs::remove_file(leader_dir.path().join("9.db"))?;
+
+    // compacted writes are only available as a snapshot
+    leader.set("key3".to_owned(), "value3".to_owned())?;
+    leader.compact()?;
+    assert_eq!(leader.changes_since(1, 100)?, None);
+    leader.set("key4".to_owned(), "value4".to_owned())?;
+    assert_eq!(leader.changes_since(4, 100)?.unwrap().len(), 1);
+
+    let (seq, mut pairs) = leader.snapshot()?;
+    pairs.sort();
+    assert_eq!(seq, 5);
+    assert_eq!(
+        pairs,
+        vec![
+            ("key2".to_owned(), "value2".to_owned()),
+            ("key3".to_owned(), "value3".to_owned()),
+            ("key4".to_owned(), "value4".to_owned()),
+        ]
+    );
+
+    follower.set("stray".to_owned(), "value".to_owned())?;
+    follower.restore(seq, pairs)?;
+    drop(follower);
+    let follower = KvStore::open(follower_dir.path())?;
+    assert_eq!(follower.last_seq(), 5);
+    assert_eq!(follower.get("stray".to_owned())?, None);
+    assert_eq!(follower.get("key4".to_owned())?, Some("value4".to_owned()));
+
+    drop(leader);
+    let leader = KvStore::open(leader_dir.path())?;
+    assert_eq!(leader.last_seq(), 5);
+    assert_eq!(leader.changes_since(1, 100)?, None);
+    Ok(())
+}
+
+// Data written before writes were numbered can only be sent as a snapshot
+#[test]
+fn unnumbered_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(
+        temp_dir.path().join("1.db"),
+        r#"{"Set":{"key":"key1","value":"value1"}}"#,
+    )?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.last_seq(), 1);
+    assert_eq!(store.changes_since(0, 100)?, None);
+
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.changes_since(1, 100)?.unwrap().len(), 1);
+    Ok(())
+}
+
+// Scan should page through live pairs in key order
+#[test]
+fn scan_in_key_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key in &["c", "a", "d", "b"] {
+        store.set(key.to_string(), format!("{}-value", key))?;
+    }
+    store.set("a".to_owned(), "new".to_owned())?;
+    store.remove("d".to_owned())?;
+
+    let page = store.scan(None, 2)?;
+    assert_eq!(
+        page,
+        vec![
+            ("a".to_owned(), "new".to_owned()),
+            ("b".to_owned(), "b-value".to_owned())
+        ]
+    );
+    let page = store.scan(Some("b".to_owned()), 2)?;
+    assert_eq!(page, vec![("c".to_owned(), "c-value".to_owned())]);
+    assert!(store.scan(Some("c".to_owned()), 2)?.is_empty());
+    Ok(())
+}
+
+// Backup should hold every write made before it, even while others go on
+#[test]
+fn backup_and_restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i % 10), format!("value{}", i))?;
+    }
+    store.remove("key9".to_owned())?;
+
+    let writer = {
+        let store = store.clone();
+        std::thread::spawn(move || -> Result<()> {
+            for i in 0..1000 {
+                store.set(format!("later{}", i), "value".to_owned())?;
+            }
+            Ok(())
+        })
+    };
+    let manifest = store.backup(backup_dir.path())?;
+    writer.join().unwrap()?;
+    assert!(manifest.seq >= 101);
+
+    // a backup goes only into an empty directory
+    assert!(store.backup(backup_dir.path()).is_err());
+
+    let restored = kvs::backup::restore(backup_dir.path(), restore_dir.path())?;
+    assert_eq!(restored, manifest);
+    let store = KvStore::open(restore_dir.path())?;
+    assert_eq!(store.last_seq(), manifest.seq);
+    for i in 0..9 {
+        assert_eq!(
+            store.get(format!("key{}", i))?,
+            Some(format!("value{}", 90 + i))
+        );
+    }
+    assert_eq!(store.get("key9".to_owned())?, None);
+    assert!(kvs::backup::restore(backup_dir.path(), restore_dir.path()).is_err());
+    Ok(())
+}
+
+// Restore should refuse a backup whose files changed
+#[test]
+fn restore_damaged_backup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let manifest = store.backup(backup_dir.path())?;
+
+    let path = backup_dir.path().join(&manifest.files[0].name);
+    let mut buf = std::fs::read(&path)?;
+    buf.pop();
+    std::fs::write(&path, buf)?;
+    assert!(kvs::backup::verify(backup_dir.path()).is_err());
+    assert!(kvs::backup::restore(backup_dir.path(), restore_dir.path()).is_err());
+    assert!(KvStore::open(restore_dir.path())?
+        .get("key1".to_owned())?
+        .is_none());
+    Ok(())
+}
+
+// Pairs should be read page by page, in key order
+#[test]
+fn iterate_pairs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..25 {
+        store.set(format!("key{:02}", i), format!("value{}", i))?;
+    }
+    store.remove("key07".to_owned())?;
+
+    let pairs = store.pairs(4).collect::<Result<Vec<_>>>()?;
+    assert_eq!(pairs.len(), 24);
+    assert_eq!(pairs[0], ("key00".to_owned(), "value0".to_owned()));
+    assert_eq!(pairs[7], ("key08".to_owned(), "value8".to_owned()));
+    assert!(pairs.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    Ok(())
+}
+
+// Export of a store should import into another one unchanged, in both formats
+#[test]
+fn export_and_import() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let pairs = vec![
+        ("plain".to_owned(), "value".to_owned()),
+        ("comma,key".to_owned(), "a \"quoted\" value".to_owned()),
+        ("lines".to_owned(), "first\nsecond\r\n".to_owned()),
+        ("empty".to_owned(), "".to_owned()),
+    ];
+    store.set_many(pairs.clone())?;
+    store.set("stale".to_owned(), "value".to_owned())?;
+    store.remove("stale".to_owned())?;
+
+    for format in &[Format::Jsonl, Format::Csv] {
+        let mut buf = Vec::new();
+        assert_eq!(export::export(&store, &mut buf, *format)?, 4);
+
+        let import_dir = TempDir::new().expect("unable to create temporary working directory");
+        let imported = KvStore::open(import_dir.path())?;
+        imported.set("kept".to_owned(), "value".to_owned())?;
+        assert_eq!(export::import(&imported, &buf[..], *format)?, 4);
+        for (key, value) in &pairs {
+            assert_eq!(imported.get(key.to_owned())?, Some(value.to_owned()));
+        }
+        assert_eq!(imported.get("kept".to_owned())?, Some("value".to_owned()));
+        assert_eq!(imported.get("stale".to_owned())?, None);
+        // followers still get imported writes, from db files
+        assert_eq!(imported.changes_since(1, 100)?.unwrap().len(), 4);
+
+        drop(imported);
+        let imported = KvStore::open(import_dir.path())?;
+        assert_eq!(imported.pairs(100).count(), 5);
+    }
+    Ok(())
+}
+
+// Import should tell which line is malformed
+#[test]
+fn import_malformed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let csv = "key,value\nkey1,value1\nkey2\n";
+    let err = export::import(&store, csv.as_bytes(), Format::Csv).unwrap_err();
+    assert!(err.to_string().contains("line 3"));
+    assert!(export::import(&store, "key1,value1\n".as_bytes(), Format::Csv).is_err());
+
+    let jsonl = "{\"key\":\"key1\",\"value\":\"value1\"}\n\n{\"key\":\"key2\"}\n";
+    let err = export::import(&store, jsonl.as_bytes(), Format::Jsonl).unwrap_err();
+    assert!(err.to_string().contains("line 3"));
+    Ok(())
+}
+
+// A store with a corrupt db file should fail to open until repaired
+#[test]
+fn inspect_and_repair() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key3".to_owned(), "value".to_owned())?;
+    drop(store);
+
+    let segments = inspect::inspect(temp_dir.path())?;
+    assert_eq!(segments.len(), 2);
+    assert_eq!(segments[0].records, 3);
+    assert_eq!(
+        segments[0].live_bytes + segments[0].stale_bytes,
+        segments[0].len
+    );
+    assert!(segments[0].stale_bytes > 0);
+    assert!(inspect::verify(temp_dir.path())?.is_empty());
+
+    // a write cut short by a crash, and a file of garbage
+    let len = segments[0].len;
+    let mut file = OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join("1.db"))?;
+    file.write_all(br#"{"Set":{"key":"key4","val"#)?;
+    std::fs::write(temp_dir.path().join("2.db"), "garbage")?;
+    let err = KvStore::open(temp_dir.path()).err().unwrap();
+    assert!(err.to_string().contains("1.db is corrupt at byte"));
+    assert_eq!(inspect::verify(temp_dir.path())?.len(), 2);
+
+    let repairs = inspect::repair(temp_dir.path())?;
+    assert_eq!(
+        repairs,
+        vec![
+            Repair::Truncated { no: 1, offset: len },
+            Repair::Dropped { no: 2 }
+        ]
+    );
+    assert!(temp_dir.path().join("1.corrupt").exists());
+    assert!(inspect::verify(temp_dir.path())?.is_empty());
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    Ok(())
+}
+
+// A store should be opened by one process at a time
+#[test]
+fn exclusive_directory() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let err = KvStore::open(temp_dir.path()).err().unwrap();
+    assert!(matches!(err.kind(), ErrorKind::Locked(_)));
+    assert!(err
+        .to_string()
+        .contains(&format!("process {}", std::process::id())));
+    assert!(inspect::repair(temp_dir.path()).is_err());
+
+    // clones share the lock, the last one dropped releases it
+    let clone = store.clone();
+    drop(store);
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    drop(clone);
+    KvStore::open(temp_dir.path())?;
+    Ok(())
+}
+
+// A lower threshold should compact sooner, and synced writes should read back alike
+#[test]
+fn compaction_threshold_and_durability() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?
+        .with_compact_threshold(4096)
+        .with_durability(Durability::Sync);
+    for iter in 0..200 {
+        store.set("key".to_owned(), format!("value{}", iter))?;
+    }
+    store.set_many(vec![("key2".to_owned(), "value".to_owned())])?;
+    store.remove("key2".to_owned())?;
+
+    let segments = inspect::inspect(temp_dir.path())?;
+    let stale: u64 = segments.iter().map(|segment| segment.stale_bytes).sum();
+    assert!(stale <= 4096);
+    assert_eq!(store.get("key".to_owned())?, Some("value199".to_owned()));
+
+    assert_eq!("sync".parse::<Durability>()?, Durability::Sync);
+    assert!("never".parse::<Durability>().is_err());
+    Ok(())
+}
+
+// Open should read only db files listed in manifest, and notice lost ones
+#[test]
+fn store_manifest() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.flush()?;
+    drop(store);
+
+    let manifest = Manifest::load(temp_dir.path())?.unwrap();
+    assert_eq!(manifest.version, FORMAT_VERSION);
+    assert_eq!(manifest.engine, "kvs");
+    assert_eq!(manifest.segments, vec![1]);
+    assert_eq!(manifest.seq, 2);
+
+    // output of a compaction cut short
+    std::fs::write(
+        temp_dir.path().join("7.db"),
+        r#"{"Set":{"key":"key1","value":"stray","seq":9}}"#,
+    )?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.last_seq(), 2);
+    assert!(!temp_dir.path().join("7.db").exists());
+    assert_eq!(
+        Manifest::load(temp_dir.path())?.unwrap().segments,
+        vec![1, 2]
+    );
+    drop(store);
+
+    // second write lost from db file but not from manifest
+    let db = temp_dir.path().join("1.db");
+    let len = inspect::read_segment(temp_dir.path(), 1)?.0[0].end;
+    OpenOptions::new().write(true).open(&db)?.set_len(len)?;
+    let err = KvStore::open(temp_dir.path()).err().unwrap();
+    assert!(err.to_string().contains("lost writes 2 to 2"));
+
+    let mut manifest = Manifest::load(temp_dir.path())?.unwrap();
+    manifest.version = FORMAT_VERSION + 1;
+    std::fs::write(
+        temp_dir.path().join("MANIFEST"),
+        serde_json::to_vec(&manifest).unwrap(),
+    )?;
+    let err = KvStore::open(temp_dir.path()).err().unwrap();
+    assert!(matches!(err.kind(), ErrorKind::UnsupportedVersion(_)));
+    Ok(())
+}
+
+// Writes racing with compaction should point at the file they went to
+#[test]
+fn writes_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?.with_compact_threshold(4 * 1024);
+    let writers: Vec<_> = (0..4)
+        .map(|thread| {
+            let store = store.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for i in 0..2000 {
+                    store.set(format!("key{}-{}", thread, i % 50), format!("value{}", i))?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for _ in 0..20 {
+        store.compact()?;
+    }
+    for writer in writers {
+        writer.join().unwrap()?;
+    }
+
+    let check = |store: &KvStore| -> Result<()> {
+        for thread in 0..4 {
+            for i in 1950..2000 {
+                assert_eq!(
+                    store.get(format!("key{}-{}", thread, i % 50))?,
+                    Some(format!("value{}", i))
+                );
+            }
+        }
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&KvStore::open(temp_dir.path())?)
+}
+
+// A follower far behind should get every write once, in order, across a racing compaction
+#[test]
+fn follower_behind_racing_compaction() -> Result<()> {
+    let leader_dir = TempDir::new().expect("unable to create temporary working directory");
+    let follower_dir = TempDir::new().expect("unable to create temporary working directory");
+    let leader = KvStore::open(leader_dir.path())?;
+    let follower = KvStore::open(follower_dir.path())?;
+    for i in 0..2000 {
+        leader.set(format!("key{}", i % 200), format!("value{}", i))?;
+    }
+
+    for round in 0..5 {
+        // follower is up to date before compaction starts
+        let changes = leader
+            .changes_since(follower.last_seq(), usize::MAX)?
+            .unwrap();
+        follower.apply(changes)?;
+
+        let writer = {
+            let leader = leader.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for i in 0..500 {
+                    leader.set(format!("key{}", i % 300), format!("{}-{}", round, i))?;
+                }
+                Ok(())
+            })
+        };
+        leader.compact()?;
+        writer.join().unwrap()?;
+        // more writes than are kept in memory, so changes come from db files
+        for i in 0..5000 {
+            leader.set(format!("later{}", i % 100), format!("{}-{}", round, i))?;
+        }
+
+        let mut last = follower.last_seq();
+        while last < leader.last_seq() {
+            let changes = leader.changes_since(last, 1024)?.unwrap();
+            for change in &changes {
+                assert_eq!(change.seq(), last + 1);
+                last += 1;
+            }
+            follower.apply(changes)?;
+        }
+    }
+    assert_eq!(follower.last_seq(), leader.last_seq());
+    let (_, mut expected) = leader.snapshot()?;
+    let (_, mut pairs) = follower.snapshot()?;
+    expected.sort();
+    pairs.sort();
+    assert_eq!(pairs, expected);
+    Ok(())
+}
@@ -0,0 +1,375 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam::sync::WaitGroup;
+use kvs::{
+    error::Result,
+    thread_pool::{
+        scope, JobError, NaiveThreadPool, Priority, QueueThreadPool, RayonThreadPool, ShutdownMode,
+        StealingThreadPool, ThreadPool,
+    },
+};
+
+// every job submitted must run exactly once
+fn spawn_counter<P: ThreadPool>(pool: P) -> Result<()> {
+    const JOBS: usize = 1000;
+    let counter = Arc::new(AtomicUsize::new(0));
+    let wg = WaitGroup::new();
+    for _ in 0..JOBS {
+        let counter = Arc::clone(&counter);
+        let wg = wg.clone();
+        pool.execute(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            drop(wg);
+        })?;
+    }
+    wg.wait();
+    assert_eq!(counter.load(Ordering::SeqCst), JOBS);
+    Ok(())
+}
+
+// pool keeps running jobs after some of them panic
+fn survive_panics<P: ThreadPool>(pool: P) -> Result<()> {
+    for _ in 0..8 {
+        pool.execute(|| panic!("job panicked on purpose"))?;
+    }
+    spawn_counter(pool)
+}
+
+#[test]
+fn naive_thread_pool() -> Result<()> {
+    spawn_counter(NaiveThreadPool::new(4)?)
+}
+
+#[test]
+fn queue_thread_pool() -> Result<()> {
+    survive_panics(QueueThreadPool::new(4)?)
+}
+
+#[test]
+fn rayon_thread_pool() -> Result<()> {
+    survive_panics(RayonThreadPool::new(4)?)
+}
+
+#[test]
+fn stealing_thread_pool() -> Result<()> {
+    spawn_counter(StealingThreadPool::new(4)?)?;
+    survive_panics(StealingThreadPool::new(4)?)
+}
+
+#[test]
+fn stealing_thread_pool_finishes_jobs_on_drop() -> Result<()> {
+    let counter = Arc::new(AtomicUsize::new(0));
+    {
+        let pool = StealingThreadPool::new(2)?;
+        for _ in 0..100 {
+            let counter = Arc::clone(&counter);
+            pool.execute(move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+            })?;
+        }
+    }
+    assert_eq!(counter.load(Ordering::SeqCst), 100);
+    Ok(())
+}
+
+// queue `jobs` slow jobs on a single worker, counting those which ran
+fn queue_slow_jobs(pool: &QueueThreadPool, jobs: usize) -> Result<Arc<AtomicUsize>> {
+    let counter = Arc::new(AtomicUsize::new(0));
+    for _ in 0..jobs {
+        let counter = Arc::clone(&counter);
+        pool.execute(move || {
+            thread::sleep(Duration::from_millis(20));
+            counter.fetch_add(1, Ordering::SeqCst);
+        })?;
+    }
+    Ok(counter)
+}
+
+#[test]
+fn queue_thread_pool_shutdown_drains_jobs() -> Result<()> {
+    let pool = QueueThreadPool::new(1)?;
+    let counter = queue_slow_jobs(&pool, 10)?;
+    pool.shutdown(Duration::from_secs(5), ShutdownMode::Drain)?;
+    // workers are joined, so every job is done
+    assert_eq!(counter.load(Ordering::SeqCst), 10);
+    Ok(())
+}
+
+#[test]
+fn queue_thread_pool_shutdown_cancels_jobs() -> Result<()> {
+    let pool = QueueThreadPool::new(1)?;
+    let counter = queue_slow_jobs(&pool, 10)?;
+    thread::sleep(Duration::from_millis(5));
+    pool.shutdown(Duration::from_secs(5), ShutdownMode::Cancel)?;
+    assert!(counter.load(Ordering::SeqCst) < 10);
+    Ok(())
+}
+
+#[test]
+fn queue_thread_pool_shutdown_times_out() -> Result<()> {
+    let pool = QueueThreadPool::new(2)?;
+    pool.execute(|| thread::sleep(Duration::from_secs(2)))?;
+    thread::sleep(Duration::from_millis(50));
+    assert!(pool
+        .shutdown(Duration::from_millis(100), ShutdownMode::Drain)
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn queue_thread_pool_reports_panics() -> Result<()> {
+    let reports = Arc::new(Mutex::new(Vec::new()));
+    let pool = {
+        let reports = Arc::clone(&reports);
+        QueueThreadPool::new(2)?.with_panic_handler(move |panic| {
+            reports.lock().unwrap().push((
+                panic.job,
+                panic.name.map(str::to_owned),
+                panic.message().map(str::to_owned),
+            ));
+        })
+    };
+
+    pool.execute(|| {})?;
+    pool.execute_named("answer", || panic!("no answer {}", 42))?;
+    // dead worker is replaced, so pool still runs jobs
+    spawn_counter(pool)?;
+
+    let reports = reports.lock().unwrap();
+    assert_eq!(
+        *reports,
+        vec![(
+            1,
+            Some("answer".to_owned()),
+            Some("no answer 42".to_owned())
+        )]
+    );
+    Ok(())
+}
+
+// counts jobs run, including ones which panicked
+fn count_jobs<P: ThreadPool>(pool: &P, workers: usize) -> Result<()> {
+    let wg = WaitGroup::new();
+    for i in 0..20 {
+        let wg = wg.clone();
+        pool.execute(move || {
+            drop(wg);
+            if i == 0 {
+                panic!("job panicked on purpose");
+            }
+        })?;
+    }
+    wg.wait();
+    // jobs count as executed only after they return
+    thread::sleep(Duration::from_millis(100));
+
+    let stats = pool.stats();
+    assert_eq!(stats.workers, workers);
+    assert_eq!(stats.queued, 0);
+    assert_eq!(stats.busy, 0);
+    assert_eq!(stats.idle, workers);
+    assert_eq!(stats.executed, 20);
+    assert_eq!(stats.panics, 1);
+    Ok(())
+}
+
+#[test]
+fn thread_pool_stats() -> Result<()> {
+    let pool = QueueThreadPool::new(4)?;
+    count_jobs(&pool, 4)?;
+    assert_eq!(pool.stats().respawns, 1);
+
+    count_jobs(&RayonThreadPool::new(4)?, 4)?;
+    count_jobs(&StealingThreadPool::new(4)?, 4)?;
+    count_jobs(&NaiveThreadPool::new(4)?, 0)
+}
+
+#[test]
+fn thread_pool_stats_show_queued_and_busy_jobs() -> Result<()> {
+    let pool = QueueThreadPool::new(1)?;
+    queue_slow_jobs(&pool, 3)?;
+    thread::sleep(Duration::from_millis(5));
+    let stats = pool.stats();
+    assert_eq!(stats.busy, 1);
+    assert_eq!(stats.idle, 0);
+    assert_eq!(stats.queued, 2);
+    Ok(())
+}
+
+#[test]
+fn queue_thread_pool_grows_and_shrinks() -> Result<()> {
+    let pool = QueueThreadPool::new(1)?
+        .with_max_workers(4)
+        .with_keep_alive(Duration::from_millis(100));
+    let counter = queue_slow_jobs(&pool, 8)?;
+    assert_eq!(pool.stats().workers, 4);
+
+    // extra workers leave after keep-alive once jobs are done
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(counter.load(Ordering::SeqCst), 8);
+    assert_eq!(pool.stats().workers, 1);
+    Ok(())
+}
+
+#[test]
+fn queue_thread_pool_resize() -> Result<()> {
+    let pool = QueueThreadPool::new(2)?;
+    pool.resize(6)?;
+    assert_eq!(pool.stats().workers, 6);
+    spawn_counter_on(&pool)?;
+
+    pool.resize(1)?;
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(pool.stats().workers, 1);
+    spawn_counter_on(&pool)?;
+
+    pool.shutdown(Duration::from_secs(5), ShutdownMode::Drain)?;
+    assert!(pool.resize(2).is_err());
+    Ok(())
+}
+
+fn spawn_counter_on(pool: &QueueThreadPool) -> Result<()> {
+    let wg = WaitGroup::new();
+    for _ in 0..100 {
+        let wg = wg.clone();
+        pool.execute(move || drop(wg))?;
+    }
+    wg.wait();
+    Ok(())
+}
+
+// results and panics come back through handles
+fn spawn_results<P: ThreadPool>(pool: P) -> Result<()> {
+    let handles = (0..10)
+        .map(|i| pool.spawn(move || i * 2))
+        .collect::<Result<Vec<_>>>()?;
+    let results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+    assert_eq!(results, (0..10).map(|i| i * 2).collect::<Vec<_>>());
+
+    let handle = pool.spawn(|| -> usize { panic!("no result") })?;
+    match handle.join() {
+        Err(err @ JobError::Panicked(_)) => assert_eq!(err.message(), Some("no result")),
+        _ => panic!("job should have panicked"),
+    }
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    let handle = pool.spawn(|| "done")?;
+    assert_eq!(runtime.block_on(handle).unwrap(), "done");
+    Ok(())
+}
+
+#[test]
+fn thread_pool_spawn() -> Result<()> {
+    spawn_results(NaiveThreadPool::new(4)?)?;
+    spawn_results(QueueThreadPool::new(4)?)?;
+    spawn_results(RayonThreadPool::new(4)?)?;
+    spawn_results(StealingThreadPool::new(4)?)
+}
+
+#[test]
+fn cancelled_jobs_have_no_result() -> Result<()> {
+    let pool = QueueThreadPool::new(1)?;
+    pool.execute(|| thread::sleep(Duration::from_millis(50)))?;
+    let handle = pool.spawn(|| 1)?;
+    pool.shutdown(Duration::from_secs(5), ShutdownMode::Cancel)?;
+    assert!(matches!(handle.join(), Err(JobError::Cancelled)));
+    Ok(())
+}
+
+#[test]
+fn scoped_jobs_borrow_local_data() -> Result<()> {
+    let pool = QueueThreadPool::new(4)?;
+    let numbers: Vec<u64> = (1..=100).collect();
+    let total = AtomicUsize::new(0);
+    let sums = scope(&pool, |s| -> Result<Vec<u64>> {
+        let handles = numbers
+            .chunks(10)
+            .map(|chunk| {
+                let total = &total;
+                s.spawn(move || {
+                    total.fetch_add(chunk.len(), Ordering::SeqCst);
+                    chunk.iter().sum::<u64>()
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(handles.into_iter().map(|h| h.join().unwrap()).collect())
+    })?;
+    assert_eq!(sums.iter().sum::<u64>(), 5050);
+    assert_eq!(total.load(Ordering::SeqCst), 100);
+    Ok(())
+}
+
+#[test]
+fn scope_waits_for_jobs() -> Result<()> {
+    let pool = QueueThreadPool::new(2)?;
+    let done = AtomicUsize::new(0);
+    scope(&pool, |s| {
+        for _ in 0..4 {
+            // handles are dropped, scope still waits
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(20));
+                done.fetch_add(1, Ordering::SeqCst);
+            })
+            .unwrap();
+        }
+    });
+    assert_eq!(done.load(Ordering::SeqCst), 4);
+    Ok(())
+}
+
+// occupy a worker until returned sender is used
+fn block_worker(pool: &QueueThreadPool) -> Result<crossbeam::channel::Sender<()>> {
+    let (gate, wait) = crossbeam::channel::bounded::<()>(0);
+    let (started, running) = crossbeam::channel::bounded::<()>(1);
+    pool.execute(move || {
+        started.send(()).unwrap();
+        wait.recv().unwrap();
+    })?;
+    running.recv().unwrap();
+    Ok(gate)
+}
+
+#[test]
+fn urgent_jobs_run_first() -> Result<()> {
+    let pool = QueueThreadPool::new(1)?;
+    // keep the only worker busy until every job is queued
+    let gate = block_worker(&pool)?;
+
+    let order = Arc::new(Mutex::new(Vec::new()));
+    for priority in [Priority::Low, Priority::Normal, Priority::High] {
+        let order = Arc::clone(&order);
+        pool.execute_with(priority, None, move || order.lock().unwrap().push(priority))?;
+    }
+    gate.send(()).unwrap();
+    pool.shutdown(Duration::from_secs(5), ShutdownMode::Drain)?;
+    assert_eq!(
+        *order.lock().unwrap(),
+        vec![Priority::High, Priority::Normal, Priority::Low]
+    );
+    Ok(())
+}
+
+#[test]
+fn late_jobs_are_dropped() -> Result<()> {
+    let pool = QueueThreadPool::new(1)?;
+    let gate = block_worker(&pool)?;
+    let late = pool.spawn_with(
+        Priority::High,
+        Some(Instant::now() + Duration::from_millis(10)),
+        || 1,
+    )?;
+    let on_time = pool.spawn_with(
+        Priority::Normal,
+        Some(Instant::now() + Duration::from_secs(5)),
+        || 2,
+    )?;
+    thread::sleep(Duration::from_millis(50));
+    gate.send(()).unwrap();
+    assert!(matches!(late.join(), Err(JobError::Cancelled)));
+    assert_eq!(on_time.join().unwrap(), 2);
+    assert_eq!(pool.stats().expired, 1);
+    Ok(())
+}